@@ -16,21 +16,79 @@
 //! Spotify it doesn't seem to revoke them at all.
 
 use clap::arg;
+use clap::Args;
 use clap::Parser;
 use clap::Subcommand;
 use clap::ValueEnum;
 use modules::playlists::clear_playlist;
 use rspotify::prelude::*;
+use rspotify::AuthCodeSpotify;
 
 pub mod modules;
+use crate::modules::availability::check_availability;
+use crate::modules::clean::clean_playlist;
+use crate::modules::decades::cap_buckets;
+use crate::modules::decades::parse_year_range;
+use crate::modules::decades::split_by_release;
+use crate::modules::decades::YearRange;
+use crate::modules::dedupe::print_duplicates;
+use crate::modules::dedupe::ReleasePreference;
+use crate::modules::discography::find_missing_releases;
+use crate::modules::discography::missing_tracks;
+use crate::modules::discography::print_report;
+use crate::modules::discography::ReportFormat;
+use crate::modules::duration::parse_duration;
+use crate::modules::duration::DurationTarget;
+use crate::modules::duration::TrackLimit;
+use crate::modules::export::export;
+use crate::modules::export::ExportColumn;
+use crate::modules::export::ExportFormat;
+use crate::modules::export::ExportSource;
+use crate::modules::features::parse_feature_filter;
+use crate::modules::features::FeatureField;
+use crate::modules::features::FeatureFilter;
+use crate::modules::features::FeatureQuery;
+use crate::modules::genres::print_genres;
+use crate::modules::genres::split_by_genre;
+use crate::modules::history::collect_plays_periodically;
+use crate::modules::history::collect_recent_plays;
+use crate::modules::history::import_streaming_history;
+use crate::modules::history::print_top_played;
+use crate::modules::history::HistoryFilter;
+use crate::modules::import::import_tracks;
+use crate::modules::import::ImportFormat;
+use crate::modules::ordering::OrderOptions;
+use crate::modules::ordering::TrackOrder;
+use crate::modules::playlists::add_new_tracks_to_playlist;
 use crate::modules::playlists::add_searched_tracks;
+use crate::modules::playlists::add_tracks_to_playlist;
+use crate::modules::playlists::create_playlist;
+use crate::modules::playlists::prepare_playlist;
+use crate::modules::playlists::resolve_playlist;
+use crate::modules::playlists::sync_split_playlists;
+use crate::modules::playlists::update_episodes;
 use crate::modules::playlists::update_everything;
 use crate::modules::playlists::update_liked;
 use crate::modules::playlists::update_recently_added;
+use crate::modules::playlists::update_smart;
 use crate::modules::playlists::update_weekly_sample;
+use crate::modules::playlists::PlaylistDetails;
+use crate::modules::playlists::SearchOptions;
+use crate::modules::playlists::SplitOptions;
+use crate::modules::releases::find_new_releases;
+use crate::modules::releases::save_release_scan;
+use crate::modules::releases::ReleaseType;
+use crate::modules::retention::RetentionPolicy;
 use crate::modules::retrieve::print_album;
 use crate::modules::retrieve::print_artist;
 use crate::modules::retrieve::print_track;
+use crate::modules::sampling::seeded_rng;
+use crate::modules::sampling::AlbumSampling;
+use crate::modules::sampling::SampleOptions;
+use crate::modules::sampling::SamplingStrategy;
+use crate::modules::settings::parse_market;
+use crate::modules::settings::Settings;
+use crate::modules::snapshots::SnapshotStore;
 use crate::modules::storage::LibraryDatabase;
 use crate::modules::token;
 
@@ -58,6 +116,227 @@ enum IdType {
     Track,
 }
 
+#[derive(Args, Clone)]
+struct CreateArgs {
+    /// Creates the playlist if it doesn't exist
+    #[arg(long, default_value_t = false)]
+    create: bool,
+
+    /// Name of the created playlist, defaults to the playlist argument
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Description of the created playlist
+    #[arg(long)]
+    description: Option<String>,
+
+    /// Makes the created playlist public
+    #[arg(long, default_value_t = false)]
+    public: bool,
+
+    /// Makes the created playlist collaborative
+    #[arg(long, default_value_t = false)]
+    collaborative: bool,
+}
+
+impl CreateArgs {
+    fn details(&self, target: &str) -> Option<PlaylistDetails> {
+        if !self.create {
+            return None;
+        }
+
+        Some(PlaylistDetails {
+            name: self.name.clone().unwrap_or_else(|| target.to_string()),
+            description: self.description.clone(),
+            public: self.public,
+            collaborative: self.collaborative,
+        })
+    }
+}
+
+#[derive(Args, Clone)]
+struct HistoryArgs {
+    /// Only uses tracks played at least this many times
+    #[arg(long)]
+    min_plays: Option<usize>,
+
+    /// Only uses tracks skipped at most this fraction of the time
+    #[arg(long)]
+    max_skip_ratio: Option<f64>,
+
+    /// Only uses tracks played in the last this many days
+    #[arg(long, value_parser = clap::value_parser!(i64).range(1..=36500))]
+    played_within_days: Option<i64>,
+
+    /// Only uses tracks not played in the last this many days, or never
+    #[arg(long, value_parser = clap::value_parser!(i64).range(1..=36500))]
+    not_played_for_days: Option<i64>,
+}
+
+impl HistoryArgs {
+    fn filter(&self) -> HistoryFilter {
+        HistoryFilter {
+            min_plays: self.min_plays,
+            max_skip_ratio: self.max_skip_ratio,
+            played_since: self
+                .played_within_days
+                .map(|days| chrono::Utc::now() - chrono::Duration::days(days)),
+            not_played_since: self
+                .not_played_for_days
+                .map(|days| chrono::Utc::now() - chrono::Duration::days(days)),
+        }
+    }
+}
+
+#[derive(Args, Clone)]
+struct DurationArgs {
+    /// Fills the playlist to a total length instead of a number of songs, e.g. 3h or 90m
+    #[arg(long, value_parser = parse_duration)]
+    duration: Option<chrono::Duration>,
+
+    /// How far the playlist length can be from the duration
+    #[arg(long, default_value = "5m", value_parser = parse_duration)]
+    tolerance: chrono::Duration,
+}
+
+impl DurationArgs {
+    fn target(&self) -> Option<DurationTarget> {
+        self.duration.map(|target| DurationTarget {
+            target,
+            tolerance: self.tolerance,
+        })
+    }
+}
+
+#[derive(Args, Clone)]
+struct RetentionArgs {
+    /// Removes songs added more than this many days ago
+    #[arg(long)]
+    max_age_days: Option<i64>,
+
+    /// Keeps at most this many albums, liked songs count as their own album
+    #[arg(long)]
+    max_albums: Option<usize>,
+
+    /// Lets the cut fall in the middle of an album instead of keeping the rest of it
+    #[arg(long, default_value_t = false)]
+    split_albums: bool,
+
+    /// Keeps liked songs whose album isn't saved for at least this many days
+    #[arg(long)]
+    keep_liked_days: Option<i64>,
+}
+
+impl RetentionArgs {
+    fn policy(&self, limit: TrackLimit) -> RetentionPolicy {
+        RetentionPolicy {
+            limit,
+            max_age: self.max_age_days.map(chrono::Duration::days),
+            max_albums: self.max_albums,
+            split_albums: self.split_albums,
+            liked_singles_age: self.keep_liked_days.map(chrono::Duration::days),
+        }
+    }
+}
+
+#[derive(Args, Clone)]
+struct FeatureArgs {
+    /// Audio feature conditions joined with "and", e.g. "energy > 0.7 and tempo 120..130"
+    #[arg(long, value_parser = parse_feature_filter)]
+    filter: Option<FeatureFilter>,
+
+    /// Sorts the songs by an audio feature
+    #[arg(long)]
+    sort_by: Option<FeatureField>,
+
+    /// Sorts from the highest value down
+    #[arg(long, default_value_t = false)]
+    descending: bool,
+}
+
+impl FeatureArgs {
+    fn query(&self) -> FeatureQuery {
+        FeatureQuery {
+            filter: self.filter.clone(),
+            sort_by: self.sort_by,
+            descending: self.descending,
+        }
+    }
+}
+
+#[derive(Args, Clone)]
+struct SampleArgs {
+    /// How songs are picked from the library
+    #[arg(long, default_value = "uniform")]
+    strategy: SamplingStrategy,
+
+    /// Days until a song is half as likely to be picked by the recency strategy
+    #[arg(long, default_value_t = 180.0)]
+    half_life_days: f64,
+
+    /// Seed for the random picks, the same seed and library give the same playlist
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Samples whole albums in track order instead of single songs
+    #[arg(long, default_value_t = false)]
+    whole_albums: bool,
+
+    /// Number of albums to sample, defaults to 10 when no duration is given
+    #[arg(long)]
+    num_albums: Option<usize>,
+
+    #[command(flatten)]
+    duration: DurationArgs,
+
+    #[command(flatten)]
+    history: HistoryArgs,
+}
+
+impl SampleArgs {
+    fn options(&self, settings: &Settings) -> SampleOptions {
+        SampleOptions {
+            strategy: self.strategy,
+            half_life_days: self.half_life_days,
+            history_filter: self.history.filter(),
+            release_preference: settings.release_preference,
+            seed: self.seed,
+            duration: self.duration.target(),
+            albums: if self.whole_albums {
+                Some(AlbumSampling {
+                    num_albums: match self.duration.duration {
+                        Some(_) => self.num_albums,
+                        None => Some(self.num_albums.unwrap_or(10)),
+                    },
+                })
+            } else {
+                None
+            },
+        }
+    }
+}
+
+#[derive(Args, Clone)]
+struct OrderArgs {
+    /// How the chosen songs are ordered in the playlist
+    #[arg(long, default_value = "keep")]
+    order: TrackOrder,
+
+    /// Songs between two songs by the same artist or from the same album when spacing
+    #[arg(long, default_value_t = 5)]
+    min_gap: usize,
+
+    /// Largest tempo change in BPM between two songs in harmonic order
+    #[arg(long, default_value_t = 6.0)]
+    max_bpm_change: f64,
+}
+
+impl OrderArgs {
+    fn options(&self, library: &LibraryDatabase) -> OrderOptions {
+        OrderOptions::new(library, self.order, self.min_gap, self.max_bpm_change)
+    }
+}
+
 #[derive(Subcommand, Clone)]
 enum Commands {
     /// Updates a playlist with the given info
@@ -91,6 +370,29 @@ enum Commands {
         /// Print
         #[arg(short, long, default_value_t = false)]
         do_print: bool,
+
+        /// Stores the results in a playlist named after the query instead of the shared one
+        #[arg(long, default_value_t = false)]
+        new: bool,
+
+        /// Orders the results by play count
+        #[arg(long, default_value_t = false)]
+        sort_by_plays: bool,
+
+        #[command(flatten)]
+        history: HistoryArgs,
+
+        #[command(flatten)]
+        features: FeatureArgs,
+
+        #[command(flatten)]
+        duration: DurationArgs,
+
+        #[command(flatten)]
+        order: OrderArgs,
+
+        #[command(flatten)]
+        create: CreateArgs,
     },
 
     /// Removes all songs in a playlist
@@ -99,6 +401,223 @@ enum Commands {
         #[arg(short, long)]
         playlist: String,
     },
+
+    /// Exports the library or a playlist to a file
+    Export {
+        /// What to export
+        #[arg(short, long)]
+        source: ExportSource,
+
+        /// Playlist ID, needed for the playlist source
+        #[arg(short, long, required_if_eq("source", "playlist"))]
+        playlist: Option<String>,
+
+        /// Output format
+        #[arg(short, long, default_value = "csv")]
+        format: ExportFormat,
+
+        /// Columns written by the csv and jsonl formats
+        #[arg(
+            short,
+            long,
+            value_delimiter = ',',
+            default_values = ["name", "artists", "album", "duration", "uri"]
+        )]
+        columns: Vec<ExportColumn>,
+
+        /// File to write to, prints the export when not given
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+
+    /// Matches tracks listed in a text, csv or m3u file and adds them to a playlist
+    Import {
+        /// File to import
+        file: String,
+
+        /// Playlist ID
+        #[arg(short, long)]
+        playlist: String,
+
+        /// Format of the file, guessed from the extension when not given
+        #[arg(short, long)]
+        format: Option<ImportFormat>,
+
+        /// Only reports the matches without changing the playlist
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+
+        #[command(flatten)]
+        create: CreateArgs,
+    },
+
+    /// Collects data from spotify that isn't kept for long
+    Collect {
+        #[command(subcommand)]
+        collect_command: CollectCommands,
+    },
+
+    /// Imports and inspects listening history
+    History {
+        #[command(subcommand)]
+        history_command: HistoryCommands,
+    },
+
+    /// Manages the playlists rspot writes to
+    Playlist {
+        #[command(subcommand)]
+        playlist_command: PlaylistCommands,
+    },
+
+    /// Shows and maps the genres the library is split into
+    Genres {
+        #[command(subcommand)]
+        genres_command: GenresCommands,
+    },
+
+    /// Shows or sets the country songs are fetched and checked for
+    Market {
+        /// Country code like AU, clears the setting when not given so the account's country is used
+        #[arg(value_parser = parse_country)]
+        country: Option<String>,
+    },
+
+    /// Lists library songs that can't be played in the market anymore and stores relinked songs
+    Availability,
+
+    /// Shows songs stored under several releases and picks which release playlists keep
+    Duplicates {
+        #[command(subcommand)]
+        duplicates_command: DuplicatesCommands,
+    },
+
+    /// Lists the releases missing from the library for artists with enough saved songs
+    Discography {
+        /// Songs an artist needs in the library to be checked
+        #[arg(long, default_value_t = 5)]
+        min_tracks: usize,
+
+        /// Kinds of release to check
+        #[arg(long, value_delimiter = ',', default_value = "album,single")]
+        types: Vec<ReleaseType>,
+
+        #[arg(short, long, default_value = "table")]
+        format: ReportFormat,
+
+        /// Replaces the songs in this playlist with the missing releases to go through
+        #[arg(short, long)]
+        playlist: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Clone)]
+enum DuplicatesCommands {
+    /// Prints the songs stored more than once, marking the release that's kept
+    List,
+
+    /// Sets which release is kept, stored in the settings
+    Prefer {
+        /// Release to keep
+        preference: ReleasePreference,
+    },
+}
+
+#[derive(Subcommand, Clone)]
+enum GenresCommands {
+    /// Prints how many songs fall under each top level genre and the biggest unmapped genres
+    List,
+
+    /// Puts a Spotify genre or a whole top level genre under another top level genre
+    Map {
+        /// Genre to map, e.g. "bedroom pop" or "Indie"
+        genre: String,
+
+        /// Top level genre it goes under, an empty name leaves the genre out
+        top_level: String,
+    },
+}
+
+#[derive(Subcommand, Clone)]
+enum CollectCommands {
+    /// Adds recently played tracks to the play history
+    Plays {
+        /// Keeps running, collecting every interval
+        #[arg(short, long, default_value_t = false)]
+        daemon: bool,
+
+        /// Seconds between collections in daemon mode
+        #[arg(short, long, default_value_t = 1800)]
+        interval: u64,
+    },
+}
+
+#[derive(Subcommand, Clone)]
+enum HistoryCommands {
+    /// Imports Streaming_History_Audio_*.json files from Spotify's extended streaming history
+    Import {
+        /// Files to import
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+
+    /// Prints the most played tracks
+    Top {
+        /// Number of tracks
+        #[arg(short, long, default_value_t = 50)]
+        num_tracks: usize,
+    },
+}
+
+#[derive(Subcommand, Clone)]
+enum PlaylistCommands {
+    /// Creates a playlist for the current user and records its ID
+    Create {
+        /// Name of the playlist
+        #[arg(short, long)]
+        name: String,
+
+        /// Key the playlist ID is recorded under, defaults to the name
+        #[arg(short, long)]
+        key: Option<String>,
+
+        /// Description of the playlist
+        #[arg(short, long)]
+        description: Option<String>,
+
+        /// Makes the playlist public
+        #[arg(long, default_value_t = false)]
+        public: bool,
+
+        /// Makes the playlist collaborative
+        #[arg(long, default_value_t = false)]
+        collaborative: bool,
+    },
+
+    /// Lists the stored snapshots of a playlist
+    History {
+        /// Playlist ID
+        playlist: String,
+    },
+
+    /// Restores a playlist to a stored snapshot
+    Restore {
+        /// Playlist ID
+        playlist: String,
+
+        /// Timestamp of the snapshot, as listed by history
+        #[arg(short, long)]
+        snapshot: i64,
+    },
+
+    /// Removes duplicates, other releases of the same song and songs that can't be played
+    Clean {
+        /// Playlist ID
+        playlist: String,
+
+        /// Only prints what would be removed
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
 }
 
 #[derive(Subcommand, Clone)]
@@ -116,9 +635,18 @@ enum UpdateCommands {
         #[arg(short, long, default_value_t = 1000)]
         num_new_songs: usize,
 
+        #[command(flatten)]
+        duration: DurationArgs,
+
+        #[command(flatten)]
+        retention: RetentionArgs,
+
         /// Removes all songs in playlist
         #[arg(short, long, default_value_t = false)]
         reset_playlist: bool,
+
+        #[command(flatten)]
+        create: CreateArgs,
     },
 
     /// Updates a given playlist with a sample of recent and old songs from the database
@@ -134,6 +662,15 @@ enum UpdateCommands {
         /// Number of old songs
         #[arg(short, long, default_value_t = 1800)]
         num_old_songs: usize,
+
+        #[command(flatten)]
+        sample: SampleArgs,
+
+        #[command(flatten)]
+        order: OrderArgs,
+
+        #[command(flatten)]
+        create: CreateArgs,
     },
 
     /// Updates a given playlist with a random sample of songs
@@ -146,9 +683,115 @@ enum UpdateCommands {
         #[arg(short, long, default_value_t = 200)]
         num_songs: usize,
 
+        #[command(flatten)]
+        sample: SampleArgs,
+
+        #[command(flatten)]
+        order: OrderArgs,
+
+        /// Days before a sampled song can be sampled again, unless the library runs out
+        #[arg(long, default_value_t = 28)]
+        cooldown_days: i64,
+
         /// Removes all songs in playlist
         #[arg(short, long, default_value_t = false)]
         reset_playlist: bool,
+
+        #[command(flatten)]
+        create: CreateArgs,
+    },
+
+    /// Fills a playlist with the library songs matching an audio feature filter
+    Smart {
+        /// Playlist ID
+        #[arg(short, long)]
+        playlist: String,
+
+        #[command(flatten)]
+        features: FeatureArgs,
+
+        /// Most songs to add, all matching songs when not given
+        #[arg(short, long)]
+        num_songs: Option<usize>,
+
+        #[command(flatten)]
+        duration: DurationArgs,
+
+        #[command(flatten)]
+        order: OrderArgs,
+
+        #[command(flatten)]
+        create: CreateArgs,
+    },
+
+    /// Syncs one playlist per top level genre, named after the genre
+    Genres {
+        /// Only syncs this genre
+        #[arg(short, long)]
+        genre: Option<String>,
+
+        /// Genres with fewer songs don't get a playlist
+        #[arg(long, default_value_t = 20)]
+        min_songs: usize,
+
+        #[command(flatten)]
+        order: OrderArgs,
+    },
+
+    /// Syncs one playlist per decade or release year range
+    Decades {
+        /// Year ranges to use instead of decades, e.g. 1990s,2000-2004,2005-
+        #[arg(long, value_delimiter = ',', value_parser = parse_year_range)]
+        ranges: Vec<YearRange>,
+
+        /// Only syncs this decade or range
+        #[arg(short, long)]
+        bucket: Option<String>,
+
+        /// Decades or ranges with fewer songs don't get a playlist
+        #[arg(long, default_value_t = 20)]
+        min_songs: usize,
+
+        /// Randomly picks this many songs from bigger decades or ranges
+        #[arg(long)]
+        max_songs: Option<usize>,
+
+        /// Seed for the random picks when capping
+        #[arg(long)]
+        seed: Option<u64>,
+
+        #[command(flatten)]
+        order: OrderArgs,
+    },
+
+    /// Adds releases by followed artists since the last run to the top of a playlist
+    Releases {
+        /// Playlist ID
+        #[arg(short, long, default_value = "new-releases")]
+        playlist: String,
+
+        /// Kinds of release to add
+        #[arg(long, value_delimiter = ',', default_value = "album,single")]
+        types: Vec<ReleaseType>,
+
+        /// Also watches every artist with a saved album
+        #[arg(long, default_value_t = false)]
+        library_artists: bool,
+
+        /// How far back to look on the first run
+        #[arg(long, default_value_t = 30)]
+        since_days: i64,
+    },
+
+    /// Fills a playlist with the latest unfinished episodes of saved shows
+    Episodes {
+        /// Playlist ID
+        #[arg(short, long, default_value = "episodes")]
+        playlist: String,
+
+        /// Number of episodes in the playlist
+        #[arg(short, long, default_value_t = 20)]
+        num_episodes: usize,
     },
 
     /// Updates a given playlist
@@ -157,12 +800,45 @@ enum UpdateCommands {
         #[arg(short, long, default_value = LIKED)]
         playlist: String,
 
+        #[command(flatten)]
+        duration: DurationArgs,
+
+        #[command(flatten)]
+        order: OrderArgs,
+
         /// Removes all songs in playlist
         #[arg(short, long, default_value_t = false)]
         reset_playlist: bool,
+
+        #[command(flatten)]
+        create: CreateArgs,
     },
 }
 
+fn parse_country(country: &str) -> Result<String, String> {
+    parse_market(country).map(|_| country.to_uppercase())
+}
+
+// Prints the error and stops with a failing exit code
+fn exit_on_error<T>(result: Result<T, String>) -> T {
+    result.unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1)
+    })
+}
+
+// The playlist a command is about to modify, snapshotted first. Stops when it can't be resolved
+// or created
+async fn modified_playlist(
+    spotify: &AuthCodeSpotify,
+    settings: &mut Settings,
+    snapshots: &SnapshotStore,
+    target: &str,
+    details: Option<PlaylistDetails>,
+) -> String {
+    exit_on_error(prepare_playlist(spotify, settings, snapshots, target, details.as_ref()).await)
+}
+
 #[tokio::main]
 
 async fn main() {
@@ -185,12 +861,18 @@ async fn main() {
         .await
         .expect("couldn't refresh user token");
 
-    let library = LibraryDatabase::new(
-        rspot_dir.join("albums.json").to_str().unwrap().to_string(),
-        rspot_dir.join("tracks.json").to_str().unwrap().to_string(),
-        rspot_dir.join("liked.json").to_str().unwrap().to_string(),
+    let mut settings = Settings::load(
+        rspot_dir
+            .join("settings.json")
+            .to_str()
+            .unwrap()
+            .to_string(),
     );
 
+    let library = LibraryDatabase::new(&rspot_dir, settings.market());
+
+    let snapshots = SnapshotStore::new(rspot_dir.join("snapshots"));
+
     let cli = CLI::parse();
 
     match &cli.command {
@@ -199,25 +881,212 @@ async fn main() {
             UpdateCommands::RecentlyAdded {
                 playlist,
                 num_new_songs,
+                duration,
+                retention,
                 reset_playlist,
-            } => update_recently_added(&spotify, &library, playlist, *num_new_songs).await,
+                create,
+            } => {
+                let playlist = modified_playlist(
+                    &spotify,
+                    &mut settings,
+                    &snapshots,
+                    playlist,
+                    create.details(playlist),
+                )
+                .await;
+                let policy = retention.policy(TrackLimit::new(*num_new_songs, duration.target()));
+                update_recently_added(&spotify, &library, &playlist, &policy).await
+            }
             UpdateCommands::Everything {
                 playlist,
                 num_new_songs,
                 num_old_songs,
+                sample,
+                order,
+                create,
             } => {
-                update_everything(&spotify, &library, playlist, *num_new_songs, *num_old_songs)
-                    .await
+                let playlist = modified_playlist(
+                    &spotify,
+                    &mut settings,
+                    &snapshots,
+                    playlist,
+                    create.details(playlist),
+                )
+                .await;
+                update_everything(
+                    &spotify,
+                    &library,
+                    &playlist,
+                    *num_new_songs,
+                    *num_old_songs,
+                    &sample.options(&settings),
+                    &order.options(&library),
+                )
+                .await
             }
             UpdateCommands::WeeklySample {
                 playlist,
                 num_songs,
                 reset_playlist,
-            } => update_weekly_sample(&spotify, &library, playlist, *num_songs).await,
+                sample,
+                order,
+                cooldown_days,
+                create,
+            } => {
+                let playlist = modified_playlist(
+                    &spotify,
+                    &mut settings,
+                    &snapshots,
+                    playlist,
+                    create.details(playlist),
+                )
+                .await;
+                update_weekly_sample(
+                    &spotify,
+                    &library,
+                    &playlist,
+                    *num_songs,
+                    &sample.options(&settings),
+                    *cooldown_days,
+                    &order.options(&library),
+                )
+                .await
+            }
+            UpdateCommands::Smart {
+                playlist,
+                features,
+                num_songs,
+                duration,
+                order,
+                create,
+            } => {
+                let playlist = modified_playlist(
+                    &spotify,
+                    &mut settings,
+                    &snapshots,
+                    playlist,
+                    create.details(playlist),
+                )
+                .await;
+                update_smart(
+                    &spotify,
+                    &library,
+                    &playlist,
+                    &features.query(),
+                    &TrackLimit::new(num_songs.unwrap_or(usize::MAX), duration.target()),
+                    &order.options(&library),
+                )
+                .await
+            }
+            UpdateCommands::Genres {
+                genre,
+                min_songs,
+                order,
+            } => {
+                let buckets = split_by_genre(&library, &settings);
+                exit_on_error(
+                    sync_split_playlists(
+                        &spotify,
+                        &mut settings,
+                        &snapshots,
+                        buckets,
+                        &SplitOptions {
+                            prefix: "genre",
+                            only: genre.clone(),
+                            min_songs: *min_songs,
+                        },
+                        &order.options(&library),
+                    )
+                    .await,
+                )
+            }
+            UpdateCommands::Decades {
+                ranges,
+                bucket,
+                min_songs,
+                max_songs,
+                seed,
+                order,
+            } => {
+                let mut buckets = split_by_release(&library, ranges);
+                if let Some(max_songs) = max_songs {
+                    let seed = seed.unwrap_or_else(rand::random);
+                    println!("Capping with seed {}", seed);
+                    buckets = cap_buckets(buckets, *max_songs, &mut seeded_rng(seed));
+                }
+                exit_on_error(
+                    sync_split_playlists(
+                        &spotify,
+                        &mut settings,
+                        &snapshots,
+                        buckets,
+                        &SplitOptions {
+                            prefix: "release",
+                            only: bucket.clone(),
+                            min_songs: *min_songs,
+                        },
+                        &order.options(&library),
+                    )
+                    .await,
+                )
+            }
+            UpdateCommands::Releases {
+                playlist,
+                types,
+                library_artists,
+                since_days,
+            } => {
+                let details = PlaylistDetails::private(
+                    String::from("New Releases"),
+                    String::from("New releases from followed artists"),
+                );
+                let playlist =
+                    modified_playlist(&spotify, &mut settings, &snapshots, playlist, Some(details))
+                        .await;
+                let (tracks, scanned_on) =
+                    find_new_releases(&spotify, &library, types, *library_artists, *since_days)
+                        .await;
+                add_new_tracks_to_playlist(&spotify, &playlist, tracks).await;
+                save_release_scan(&library, scanned_on)
+            }
+            UpdateCommands::Episodes {
+                playlist,
+                num_episodes,
+            } => {
+                let details = PlaylistDetails::private(
+                    String::from("Episodes"),
+                    String::from("Unfinished episodes of saved shows"),
+                );
+                let playlist =
+                    modified_playlist(&spotify, &mut settings, &snapshots, playlist, Some(details))
+                        .await;
+                update_episodes(&spotify, &library, &playlist, *num_episodes).await
+            }
             UpdateCommands::Liked {
                 playlist,
+                duration,
+                order,
                 reset_playlist,
-            } => update_liked(&spotify, &library, playlist).await,
+                create,
+            } => {
+                let playlist = modified_playlist(
+                    &spotify,
+                    &mut settings,
+                    &snapshots,
+                    playlist,
+                    create.details(playlist),
+                )
+                .await;
+                update_liked(
+                    &spotify,
+                    &library,
+                    &playlist,
+                    settings.release_preference,
+                    duration.target(),
+                    &order.options(&library),
+                )
+                .await
+            }
         },
 
         Commands::Print { print_id, id_type } => match id_type {
@@ -229,11 +1098,189 @@ async fn main() {
             query,
             playlist,
             do_print,
+            new,
+            sort_by_plays,
+            history,
+            features,
+            duration,
+            order,
+            create,
         } => {
-            add_searched_tracks(&spotify, &library, playlist, query, *do_print).await;
+            let playlist = if *new {
+                let details = PlaylistDetails::private(query.clone(), query.clone());
+                let key = format!("search:{}", query);
+                modified_playlist(&spotify, &mut settings, &snapshots, &key, Some(details)).await
+            } else {
+                modified_playlist(
+                    &spotify,
+                    &mut settings,
+                    &snapshots,
+                    playlist,
+                    create.details(playlist),
+                )
+                .await
+            };
+            add_searched_tracks(
+                &spotify,
+                &library,
+                &playlist,
+                query,
+                *do_print,
+                &SearchOptions {
+                    history_filter: history.filter(),
+                    sort_by_plays: *sort_by_plays,
+                    release_preference: settings.release_preference,
+                    features: features.query(),
+                    duration: duration.target(),
+                },
+                &order.options(&library),
+            )
+            .await;
         }
         Commands::Clear { playlist } => {
-            clear_playlist(&spotify, playlist).await;
+            let playlist =
+                modified_playlist(&spotify, &mut settings, &snapshots, playlist, None).await;
+            clear_playlist(&spotify, &playlist).await;
+        }
+        Commands::Export {
+            source,
+            playlist,
+            format,
+            columns,
+            output,
+        } => {
+            let playlist = match playlist {
+                Some(playlist) => Some(exit_on_error(
+                    resolve_playlist(&spotify, &mut settings, playlist, None).await,
+                )),
+                None => None,
+            };
+            exit_on_error(
+                export(
+                    &spotify,
+                    &library,
+                    *source,
+                    playlist.as_deref(),
+                    *format,
+                    columns,
+                    output.as_deref(),
+                )
+                .await,
+            );
+        }
+        Commands::Import {
+            file,
+            playlist,
+            format,
+            dry_run,
+            create,
+        } => {
+            // A dry run doesn't create or snapshot anything
+            let playlist = if *dry_run {
+                None
+            } else {
+                Some(
+                    modified_playlist(
+                        &spotify,
+                        &mut settings,
+                        &snapshots,
+                        playlist,
+                        create.details(playlist),
+                    )
+                    .await,
+                )
+            };
+            exit_on_error(
+                import_tracks(&spotify, &library, playlist.as_deref(), file, *format).await,
+            );
+        }
+        Commands::Collect { collect_command } => match collect_command {
+            CollectCommands::Plays { daemon, interval } => {
+                if *daemon {
+                    collect_plays_periodically(&spotify, &library, *interval).await;
+                } else {
+                    exit_on_error(collect_recent_plays(&spotify, &library).await);
+                }
+            }
+        },
+        Commands::History { history_command } => match history_command {
+            HistoryCommands::Import { files } => import_streaming_history(&library, files),
+            HistoryCommands::Top { num_tracks } => print_top_played(&library, *num_tracks),
+        },
+        Commands::Playlist { playlist_command } => match playlist_command {
+            PlaylistCommands::Create {
+                name,
+                key,
+                description,
+                public,
+                collaborative,
+            } => {
+                let details = PlaylistDetails {
+                    name: name.clone(),
+                    description: description.clone(),
+                    public: *public,
+                    collaborative: *collaborative,
+                };
+                let key = key.as_ref().unwrap_or(name);
+                exit_on_error(create_playlist(&spotify, &mut settings, key, &details).await);
+            }
+            PlaylistCommands::History { playlist } => {
+                let playlist =
+                    exit_on_error(resolve_playlist(&spotify, &mut settings, playlist, None).await);
+                snapshots.print_history(&playlist);
+            }
+            PlaylistCommands::Restore { playlist, snapshot } => {
+                let playlist =
+                    exit_on_error(resolve_playlist(&spotify, &mut settings, playlist, None).await);
+                exit_on_error(snapshots.restore(&spotify, &playlist, *snapshot).await);
+            }
+            PlaylistCommands::Clean { playlist, dry_run } => {
+                let playlist = if *dry_run {
+                    exit_on_error(resolve_playlist(&spotify, &mut settings, playlist, None).await)
+                } else {
+                    modified_playlist(&spotify, &mut settings, &snapshots, playlist, None).await
+                };
+                clean_playlist(&spotify, &playlist, library.market(), *dry_run).await;
+            }
+        },
+        Commands::Genres { genres_command } => match genres_command {
+            GenresCommands::List => print_genres(&library, &settings),
+            GenresCommands::Map { genre, top_level } => settings.map_genre(genre, top_level),
+        },
+        Commands::Market { country } => {
+            settings.set_market(country.clone());
+            match &settings.market {
+                Some(country) => println!("Using the {} market", country),
+                None => println!("Using the account's market"),
+            }
+        }
+        Commands::Availability => check_availability(&spotify, &library).await,
+        Commands::Duplicates { duplicates_command } => match duplicates_command {
+            DuplicatesCommands::List => print_duplicates(&library, &settings),
+            DuplicatesCommands::Prefer { preference } => {
+                settings.set_release_preference(*preference)
+            }
+        },
+        Commands::Discography {
+            min_tracks,
+            types,
+            format,
+            playlist,
+        } => {
+            let reports = find_missing_releases(&spotify, &library, *min_tracks, types).await;
+            print_report(&reports, *format);
+            if let Some(playlist) = playlist {
+                let details = PlaylistDetails::private(
+                    String::from("Discography Review"),
+                    String::from("Releases missing from the library"),
+                );
+                let playlist =
+                    modified_playlist(&spotify, &mut settings, &snapshots, playlist, Some(details))
+                        .await;
+                let tracks = missing_tracks(&spotify, &library, &reports).await;
+                clear_playlist(&spotify, &playlist).await;
+                add_tracks_to_playlist(&spotify, &playlist, tracks, None).await;
+            }
         }
     }
 }
@@ -0,0 +1,59 @@
+use itertools::Itertools;
+use rspotify::{model::TrackId, AuthCodeSpotify};
+
+use super::{
+    conversion::track_ids_to_tracks, playlists::playlist_track_id, storage::LibraryDatabase,
+};
+
+// Fetches every library track again for the market, storing the relinked versions and listing
+// the tracks that can't be played there anymore
+pub async fn check_availability(spotify: &AuthCodeSpotify, library: &LibraryDatabase) {
+    let stored = library.retrieve_tracks().into_keys().sorted().collect_vec();
+    let track_ids = stored
+        .iter()
+        .filter_map(|id| TrackId::from_id(id.as_str()).ok())
+        .collect_vec();
+    let tracks = track_ids_to_tracks(spotify, track_ids, Some(library.market())).await;
+
+    // A relinked track has the id of the version playable in the market and keeps the stored id
+    // in linked_from, which is what it's stored under. Tracks Spotify no longer has are skipped
+    // and stay as they were
+    let relinked = tracks
+        .iter()
+        .filter(|track| track.id != playlist_track_id(track))
+        .count();
+
+    let unavailable = tracks
+        .iter()
+        .filter(|track| track.is_playable == Some(false))
+        .sorted_by_key(|track| {
+            (
+                track.artists.get(0).map(|artist| artist.name.clone()),
+                track.name.clone(),
+            )
+        })
+        .collect_vec();
+    for track in &unavailable {
+        println!(
+            "{} - {} ({}){}",
+            track
+                .artists
+                .get(0)
+                .map_or("", |artist| artist.name.as_str()),
+            track.name,
+            track.album.name,
+            match &track.restrictions {
+                Some(restriction) => format!(", restricted by {:?}", restriction.reason),
+                None => String::new(),
+            }
+        );
+    }
+    println!(
+        "{} of {} library tracks can't be played in the market, {} were relinked to another version",
+        unavailable.len(),
+        stored.len(),
+        relinked
+    );
+
+    library.update_tracks(tracks);
+}
@@ -0,0 +1,171 @@
+use itertools::Itertools;
+use rspotify::{
+    model::{Market, PlayableId, PlayableItem, PlaylistId},
+    prelude::*,
+    AuthCodeSpotify,
+};
+use std::collections::HashSet;
+
+use super::{
+    dedupe::canonical_key,
+    local::local_key,
+    playlists::{get_playlist_entries, playlist_track_id, PlaylistEntry},
+    removal::remove_entries,
+};
+
+// Why an item should come out of the playlist
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Problem {
+    // The same track already came earlier
+    Duplicate,
+    // Another release of the same recording already came earlier
+    SameRecording,
+    // Spotify won't play it in the market
+    Unplayable,
+    // Spotify no longer has the item at all
+    Unavailable,
+}
+
+impl Problem {
+    fn description(&self) -> &str {
+        match self {
+            Problem::Duplicate => "Duplicates",
+            Problem::SameRecording => "Other releases of an earlier song",
+            Problem::Unplayable => "Not playable in the market",
+            Problem::Unavailable => "Removed from Spotify",
+        }
+    }
+}
+
+struct Finding {
+    position: u32,
+    problem: Problem,
+    name: String,
+    // Items Spotify has no id for can't be removed through the API
+    id: Option<PlayableId<'static>>,
+}
+
+// Goes through the playlist in order so the first copy of anything is the one that stays. Local
+// files are kept unless the same file is in there twice
+fn find_problems(entries: &[PlaylistEntry]) -> Vec<Finding> {
+    let mut seen_local = HashSet::new();
+    let mut seen_ids = HashSet::new();
+    let mut seen_recordings = HashSet::new();
+    let mut findings = Vec::new();
+    for entry in entries {
+        let finding = |problem, id| Finding {
+            position: entry.position,
+            problem,
+            name: entry.name(),
+            id,
+        };
+
+        let track = match &entry.item {
+            Some(PlayableItem::Track(track)) => track,
+            Some(PlayableItem::Episode(episode)) => {
+                let id = PlayableId::Episode(episode.id.clone_static());
+                if seen_ids.contains(&id) {
+                    findings.push(finding(Problem::Duplicate, Some(id)));
+                } else {
+                    seen_ids.insert(id);
+                }
+                continue;
+            }
+            None => {
+                findings.push(finding(Problem::Unavailable, None));
+                continue;
+            }
+        };
+
+        if entry.is_local || track.is_local {
+            let key = local_key(track);
+            if !seen_local.insert(key) {
+                findings.push(finding(Problem::Duplicate, None));
+            }
+            continue;
+        }
+        // Relinked tracks are removed and compared by the id the playlist has for them
+        let Some(track_id) = playlist_track_id(track) else {
+            findings.push(finding(Problem::Unavailable, None));
+            continue;
+        };
+        let id = PlayableId::Track(track_id);
+
+        if seen_ids.contains(&id) {
+            findings.push(finding(Problem::Duplicate, Some(id)));
+        } else if track.is_playable == Some(false) {
+            findings.push(finding(Problem::Unplayable, Some(id)));
+        } else if seen_recordings.contains(&canonical_key(track)) {
+            findings.push(finding(Problem::SameRecording, Some(id)));
+        } else {
+            seen_recordings.insert(canonical_key(track));
+            seen_ids.insert(id);
+        }
+    }
+    findings
+}
+
+fn print_report(findings: &[Finding]) {
+    for (problem, group) in &findings
+        .iter()
+        .sorted_by_key(|finding| (finding.problem, finding.position))
+        .group_by(|finding| finding.problem)
+    {
+        println!("{}:", problem.description());
+        for finding in group {
+            println!(
+                "  {:>5}  {}{}",
+                finding.position + 1,
+                finding.name,
+                if finding.id.is_none() {
+                    "  (has to be removed by hand)"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+}
+
+fn removable(findings: Vec<Finding>) -> Vec<(u32, PlayableId<'static>)> {
+    findings
+        .into_iter()
+        .filter_map(|finding| Some((finding.position, finding.id?)))
+        .collect_vec()
+}
+
+pub async fn clean_playlist(
+    spotify: &AuthCodeSpotify,
+    playlist_id: &str,
+    market: Market,
+    dry_run: bool,
+) {
+    let playlist_id = PlaylistId::from_id(playlist_id).unwrap();
+    let entries = get_playlist_entries(spotify, &playlist_id, Some(market)).await;
+
+    let findings = find_problems(&entries);
+    if findings.is_empty() {
+        println!("Nothing to clean in {} items", entries.len());
+        return;
+    }
+    print_report(&findings);
+
+    let manual = findings
+        .iter()
+        .filter(|finding| finding.id.is_none())
+        .count();
+    if dry_run {
+        println!("Would remove {} items", findings.len() - manual);
+        return;
+    }
+
+    // The problems are found again on the playlist as it is when the removal is made
+    let removed = remove_entries(spotify, &playlist_id, Some(market), |entries| {
+        removable(find_problems(entries))
+    })
+    .await;
+    println!("Removed {} items", removed.len());
+    if manual > 0 {
+        println!("{} items have to be removed by hand", manual);
+    }
+}
@@ -2,14 +2,18 @@ use std::collections::HashMap;
 
 use itertools::Itertools;
 use rspotify::{
-    model::{FullAlbum, FullTrack, SavedAlbum, SavedTrack, TrackId},
+    model::{FullAlbum, FullTrack, Market, SavedAlbum, SavedTrack, TrackId},
     prelude::*,
     AuthCodeSpotify,
 };
 
+use super::retrieve::fetch_in_batches;
+
+// With a market the tracks come back relinked to the version playable there, with is_playable set
 pub async fn albums_to_tracks(
     spotify: &AuthCodeSpotify,
     current_albums: Vec<FullAlbum>,
+    market: Option<Market>,
 ) -> Vec<FullTrack> {
     let track_ids: Vec<TrackId> = current_albums
         .iter()
@@ -19,14 +23,10 @@ pub async fn albums_to_tracks(
 
     let mut tracks = Vec::new();
     for group in track_ids.chunks(50) {
-        let mut current_tracks: Vec<FullTrack> = spotify
-            .tracks(group.to_vec().into_iter(), None)
+        let mut current_tracks = spotify
+            .tracks(group.to_vec().into_iter(), market)
             .await
-            .unwrap()
-            .iter()
-            .filter_map(|track| if !track.is_local { Some(track) } else { None })
-            .cloned()
-            .collect();
+            .unwrap();
         tracks.append(current_tracks.as_mut());
     }
     tracks
@@ -55,6 +55,19 @@ pub fn id_to_playable_ids<'a>(ids: &Vec<TrackId<'a>>) -> Vec<PlayableId<'a>> {
 }
 
 // Man I hate this code duplication but wat can I do
+// Playlist descriptions come back from the api html escaped, and sending them back as they are
+// escapes them again. &amp; goes last so "&amp;lt;" stays "&lt;"
+pub fn unescape_html(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&#x27;", "'")
+        .replace("&#39;", "'")
+        .replace("&#x2F;", "/")
+        .replace("&#47;", "/")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
 pub fn saved_tracks_to_tracks(saved: Vec<SavedTrack>) -> Vec<FullTrack> {
     saved
         .into_iter()
@@ -72,27 +85,18 @@ pub fn saved_albums_to_albums(saved: Vec<SavedAlbum>) -> Vec<FullAlbum> {
 pub async fn track_ids_to_tracks(
     spotify: &AuthCodeSpotify,
     track_ids: Vec<TrackId<'_>>,
+    market: Option<Market>,
 ) -> Vec<FullTrack> {
-    let paginated_tracks = track_ids.chunks(50);
-    let mut tracks = Vec::new();
-    for group in paginated_tracks {
-        let mut current_tracks: Vec<FullTrack> = spotify
-            .tracks(group.to_vec().into_iter(), None)
-            .await
-            .unwrap()
-            .iter()
-            .filter_map(|track| if !track.is_local { Some(track) } else { None })
-            .cloned()
-            .collect();
-        tracks.append(current_tracks.as_mut());
-    }
-
-    tracks
+    fetch_in_batches(&track_ids, 50, "track", move |ids| {
+        spotify.tracks(ids, market)
+    })
+    .await
 }
 
 pub async fn saved_albums_to_saved_tracks(
     spotify: &AuthCodeSpotify,
     recent_albums: Vec<SavedAlbum>,
+    market: Option<Market>,
 ) -> Vec<SavedTrack> {
     let recent_album_tracks = albums_to_tracks(
         spotify,
@@ -100,22 +104,49 @@ pub async fn saved_albums_to_saved_tracks(
             .iter()
             .map(|album| album.album.clone())
             .collect_vec(),
+        market,
     )
     .await;
 
+    // A relinked track can belong to another release of the album, so the album's track ids are
+    // checked as well as the album id
     let mut album_to_time = HashMap::new();
+    let mut track_to_time = HashMap::new();
     for album in recent_albums {
+        for track in &album.album.tracks.items {
+            if let Some(id) = &track.id {
+                track_to_time.insert(id.id().to_owned(), album.added_at);
+            }
+        }
         album_to_time.insert(album.album.id.id().to_owned(), album.added_at);
     }
 
     recent_album_tracks
         .into_iter()
-        .map(|track| SavedTrack {
-            track: track.clone(),
-            added_at: album_to_time
-                .get(track.album.id.unwrap().id())
-                .unwrap()
-                .to_owned(),
+        .filter_map(|track| {
+            let added_at = track
+                .album
+                .id
+                .as_ref()
+                .and_then(|id| album_to_time.get(id.id()))
+                .or_else(|| track_to_time.get(track.id.as_ref()?.id()))?
+                .to_owned();
+            Some(SavedTrack { track, added_at })
         })
         .collect_vec()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescapes_descriptions() {
+        assert_eq!(
+            unescape_html("Rock &amp; Roll &#x27;n&#x27; &quot;Blues&quot; &lt;3 AC&#x2F;DC"),
+            "Rock & Roll 'n' \"Blues\" <3 AC/DC"
+        );
+        assert_eq!(unescape_html("&amp;lt;"), "&lt;");
+        assert_eq!(unescape_html("Plain"), "Plain");
+    }
+}
@@ -0,0 +1,300 @@
+use rand::{seq::SliceRandom, Rng};
+use rspotify::{model::FullTrack, prelude::*};
+use std::collections::HashMap;
+
+use super::storage::LibraryDatabase;
+
+// A release date only as precise as Spotify knows it, a year precision date has no month or day
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ReleaseDate {
+    pub year: i32,
+    pub month: Option<u32>,
+    pub day: Option<u32>,
+}
+
+// Dates come as "1994", "1994-03" or "1994-03-21" depending on the precision. Spotify uses year 0
+// when it doesn't know, which is treated as no date
+pub fn parse_release_date(date: &str, precision: Option<&str>) -> Option<ReleaseDate> {
+    let mut parts = date.split('-');
+    let year = parts.next()?.parse::<i32>().ok().filter(|year| *year > 0)?;
+    let month = parts.next().and_then(|month| month.parse::<u32>().ok());
+    let day = parts.next().and_then(|day| day.parse::<u32>().ok());
+    let date = match precision {
+        Some("year") => ReleaseDate {
+            year,
+            month: None,
+            day: None,
+        },
+        Some("month") => ReleaseDate {
+            year,
+            month,
+            day: None,
+        },
+        _ => ReleaseDate { year, month, day },
+    };
+    Some(date)
+}
+
+// The stored album has the full release date, the track's copy of the album is the fallback
+fn track_release_date(
+    track: &FullTrack,
+    album_dates: &HashMap<String, ReleaseDate>,
+) -> Option<ReleaseDate> {
+    track
+        .album
+        .id
+        .as_ref()
+        .and_then(|id| album_dates.get(id.id()).copied())
+        .or_else(|| {
+            parse_release_date(
+                track.album.release_date.as_ref()?,
+                track.album.release_date_precision.as_deref(),
+            )
+        })
+}
+
+// An inclusive range of release years, either end can be open
+#[derive(Clone, Debug)]
+pub struct YearRange {
+    pub name: String,
+    pub start: Option<i32>,
+    pub end: Option<i32>,
+}
+
+impl YearRange {
+    fn decade(year: i32) -> YearRange {
+        let start = year / 10 * 10;
+        YearRange {
+            name: format!("{}s", start),
+            start: Some(start),
+            end: Some(start + 9),
+        }
+    }
+
+    fn contains(&self, year: i32) -> bool {
+        self.start.map_or(true, |start| start <= year) && self.end.map_or(true, |end| year <= end)
+    }
+}
+
+fn parse_year(text: &str) -> Result<Option<i32>, String> {
+    if text.is_empty() {
+        return Ok(None);
+    }
+    text.parse::<i32>()
+        .map(Some)
+        .map_err(|_| format!("Invalid year {}", text))
+}
+
+// Takes a decade like "1990s" or a range like "1990-2004", "-1969" or "2010-"
+pub fn parse_year_range(text: &str) -> Result<YearRange, String> {
+    let text = text.trim();
+    if let Some(decade) = text.strip_suffix('s') {
+        let year = parse_year(decade)?.ok_or_else(|| format!("Invalid decade {}", text))?;
+        let mut range = YearRange::decade(year);
+        range.name = text.to_string();
+        return Ok(range);
+    }
+
+    let (start, end) = text
+        .split_once('-')
+        .ok_or_else(|| format!("Invalid year range {}", text))?;
+    Ok(YearRange {
+        name: text.to_string(),
+        start: parse_year(start.trim())?,
+        end: parse_year(end.trim())?,
+    })
+}
+
+// Library tracks in each range oldest release first, or in each decade when no ranges are given.
+// A track goes in every range it falls in so custom ranges can overlap
+pub fn split_by_release(
+    library: &LibraryDatabase,
+    ranges: &[YearRange],
+) -> HashMap<String, Vec<FullTrack>> {
+    let album_dates = library
+        .retrieve_albums()
+        .into_iter()
+        .filter_map(|(id, album)| {
+            let date =
+                parse_release_date(&album.release_date, Some(&album.release_date_precision))?;
+            Some((id, date))
+        })
+        .collect::<HashMap<_, _>>();
+
+    bucket_by_release(
+        library.retrieve_tracks().into_values(),
+        &album_dates,
+        ranges,
+    )
+}
+
+fn bucket_by_release(
+    tracks: impl IntoIterator<Item = FullTrack>,
+    album_dates: &HashMap<String, ReleaseDate>,
+    ranges: &[YearRange],
+) -> HashMap<String, Vec<FullTrack>> {
+    let mut dated = Vec::new();
+    let mut undated = 0;
+    for track in tracks {
+        match track_release_date(&track, album_dates) {
+            Some(date) => dated.push((date, track)),
+            None => undated += 1,
+        }
+    }
+    if undated > 0 {
+        println!("{} tracks have no release date", undated);
+    }
+
+    // Less precise dates go first in their year, then albums stay together in track order
+    dated.sort_by(|(a_date, a), (b_date, b)| {
+        (a_date, &a.album.name, a.disc_number, a.track_number).cmp(&(
+            b_date,
+            &b.album.name,
+            b.disc_number,
+            b.track_number,
+        ))
+    });
+
+    let mut split: HashMap<String, Vec<FullTrack>> = HashMap::new();
+    for (date, track) in dated {
+        if ranges.is_empty() {
+            let decade = YearRange::decade(date.year);
+            split.entry(decade.name).or_default().push(track);
+            continue;
+        }
+        for range in ranges.iter().filter(|range| range.contains(date.year)) {
+            split
+                .entry(range.name.clone())
+                .or_default()
+                .push(track.clone());
+        }
+    }
+    split
+}
+
+// Randomly keeps max_songs tracks of each bucket, keeping them in release order
+pub fn cap_buckets<R: Rng>(
+    mut buckets: HashMap<String, Vec<FullTrack>>,
+    max_songs: usize,
+    rng: &mut R,
+) -> HashMap<String, Vec<FullTrack>> {
+    let mut names = buckets.keys().cloned().collect::<Vec<_>>();
+    // Sorted so a seed caps every bucket the same way each run
+    names.sort();
+    for name in names {
+        let tracks = buckets.get_mut(&name).unwrap();
+        if tracks.len() <= max_songs {
+            continue;
+        }
+        let mut keep = (0..tracks.len()).collect::<Vec<_>>();
+        keep.shuffle(rng);
+        keep.truncate(max_songs);
+        keep.sort();
+        *tracks = keep
+            .into_iter()
+            .map(|index| tracks[index].clone())
+            .collect();
+    }
+    buckets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::fixtures::track;
+
+    fn dated(id: &str, album: &str, date: Option<&str>, precision: &str) -> FullTrack {
+        let mut track = track(id, id, "Artist", album, 200000);
+        track.album.release_date = date.map(String::from);
+        track.album.release_date_precision = Some(precision.to_string());
+        track
+    }
+
+    fn names(buckets: &HashMap<String, Vec<FullTrack>>, bucket: &str) -> Vec<String> {
+        buckets[bucket]
+            .iter()
+            .map(|track| track.name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn release_dates_keep_their_precision() {
+        let date = |year, month, day| Some(ReleaseDate { year, month, day });
+        assert_eq!(
+            parse_release_date("1994", Some("year")),
+            date(1994, None, None)
+        );
+        assert_eq!(
+            parse_release_date("1994-03", Some("month")),
+            date(1994, Some(3), None)
+        );
+        assert_eq!(
+            parse_release_date("1994-03-21", Some("day")),
+            date(1994, Some(3), Some(21))
+        );
+        assert_eq!(
+            parse_release_date("1994-01-01", Some("year")),
+            date(1994, None, None)
+        );
+        assert_eq!(parse_release_date("0000", Some("year")), None);
+        assert_eq!(parse_release_date("", None), None);
+    }
+
+    #[test]
+    fn tracks_go_in_their_decade() {
+        let tracks = vec![
+            dated("month", "B", Some("1994-03"), "month"),
+            dated("day", "C", Some("1989-12-31"), "day"),
+            dated("year", "A", Some("1994"), "year"),
+            dated("undated", "D", None, "day"),
+            dated("unknown", "E", Some("0000"), "year"),
+            dated("stored", "Old", Some("2020-01-01"), "day"),
+        ];
+        // The stored album's date wins over the track's copy
+        let album_dates = HashMap::from([(
+            String::from("OldAlbum"),
+            parse_release_date("1975", Some("year")).unwrap(),
+        )]);
+
+        let buckets = bucket_by_release(tracks, &album_dates, &[]);
+        assert_eq!(buckets.len(), 3);
+        // Year only dates come before the more precise ones in the same year
+        assert_eq!(names(&buckets, "1990s"), ["year", "month"]);
+        assert_eq!(names(&buckets, "1980s"), ["day"]);
+        assert_eq!(names(&buckets, "1970s"), ["stored"]);
+    }
+
+    #[test]
+    fn tracks_go_in_every_range_they_fall_in() {
+        let tracks = vec![
+            dated("1989", "A", Some("1989"), "year"),
+            dated("1994", "B", Some("1994-06"), "month"),
+            dated("2011", "C", Some("2011-02-03"), "day"),
+        ];
+        let ranges = ["1989-1994", "1990s", "-1989", "2010-"]
+            .iter()
+            .map(|text| parse_year_range(text).unwrap())
+            .collect::<Vec<_>>();
+
+        let buckets = bucket_by_release(tracks, &HashMap::new(), &ranges);
+        assert_eq!(names(&buckets, "1989-1994"), ["1989", "1994"]);
+        assert_eq!(names(&buckets, "1990s"), ["1994"]);
+        assert_eq!(names(&buckets, "-1989"), ["1989"]);
+        assert_eq!(names(&buckets, "2010-"), ["2011"]);
+    }
+
+    #[test]
+    fn parses_decades_and_ranges() {
+        let range = parse_year_range("1990s").unwrap();
+        assert_eq!((range.start, range.end), (Some(1990), Some(1999)));
+        let range = parse_year_range("1995s").unwrap();
+        assert_eq!((range.start, range.end), (Some(1990), Some(1999)));
+        let range = parse_year_range("-1969").unwrap();
+        assert_eq!((range.start, range.end), (None, Some(1969)));
+        let range = parse_year_range("2010-").unwrap();
+        assert_eq!((range.start, range.end), (Some(2010), None));
+        assert!(parse_year_range("1990").is_err());
+        assert!(parse_year_range("nineties").is_err());
+        assert!(parse_year_range("s").is_err());
+    }
+}
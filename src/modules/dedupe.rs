@@ -0,0 +1,227 @@
+use clap::ValueEnum;
+use itertools::Itertools;
+use rspotify::{model::FullTrack, prelude::*};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::{import::normalize, settings::Settings, storage::LibraryDatabase};
+
+// Which release of a song is kept when the same recording is on several of them
+#[derive(
+    Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize, Serialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReleasePreference {
+    /// The earliest release, usually the original album
+    #[default]
+    Original,
+    /// The latest release, usually a remaster or deluxe edition
+    Latest,
+    /// Studio albums over singles and compilations, then the earliest
+    Album,
+    /// The most popular release
+    Popular,
+}
+
+fn album_type_rank(track: &FullTrack) -> u8 {
+    match track.album.album_type.as_deref() {
+        Some("album") => 0,
+        Some("single") => 1,
+        Some("compilation") => 2,
+        _ => 3,
+    }
+}
+
+impl ReleasePreference {
+    // Whether a should be kept over b, the track id settles ties so the choice is stable. Releases
+    // without a date lose to dated ones
+    fn prefers(&self, a: &FullTrack, b: &FullTrack) -> bool {
+        let date = |track: &FullTrack| {
+            let date = track.album.release_date.clone().unwrap_or_default();
+            (date.is_empty(), date)
+        };
+        let id = |track: &FullTrack| {
+            track
+                .id
+                .as_ref()
+                .map(|id| id.id().to_string())
+                .unwrap_or_default()
+        };
+        let ordering = match self {
+            ReleasePreference::Original => {
+                (date(a), album_type_rank(a)).cmp(&(date(b), album_type_rank(b)))
+            }
+            ReleasePreference::Latest => {
+                let (a_missing, a_date) = date(a);
+                let (b_missing, b_date) = date(b);
+                (a_missing, b_date).cmp(&(b_missing, a_date))
+            }
+            ReleasePreference::Album => {
+                (album_type_rank(a), date(a)).cmp(&(album_type_rank(b), date(b)))
+            }
+            ReleasePreference::Popular => b.popularity.cmp(&a.popularity),
+        };
+        ordering.then_with(|| id(a).cmp(&id(b))).is_lt()
+    }
+}
+
+// Tracks with the same key are the same recording. The ISRC identifies it across releases, and
+// when a track has none the artist, title and length to the nearest couple of seconds are used
+pub fn canonical_key(track: &FullTrack) -> String {
+    if let Some(isrc) = track.external_ids.get("isrc") {
+        if !isrc.is_empty() {
+            return format!("isrc:{}", isrc.to_uppercase());
+        }
+    }
+    let artist = track
+        .artists
+        .get(0)
+        .map(|artist| normalize(&artist.name))
+        .unwrap_or_default();
+    format!(
+        "song:{}|{}|{}",
+        artist,
+        normalize(&track.name),
+        (track.duration.num_milliseconds() + 1000) / 2000
+    )
+}
+
+// Keeps one release of each recording. The kept release takes the place of the first copy so the
+// order of the rest doesn't change
+pub fn dedupe_tracks(tracks: Vec<FullTrack>, preference: ReleasePreference) -> Vec<FullTrack> {
+    let mut best: HashMap<String, usize> = HashMap::new();
+    let mut keys = Vec::new();
+    for (index, track) in tracks.iter().enumerate() {
+        let key = canonical_key(track);
+        match best.get(&key) {
+            Some(kept) if !preference.prefers(track, &tracks[*kept]) => {}
+            Some(_) => {
+                best.insert(key, index);
+            }
+            None => {
+                best.insert(key.clone(), index);
+                keys.push(key);
+            }
+        }
+    }
+
+    keys.iter()
+        .map(|key| tracks[best[key]].clone())
+        .collect_vec()
+}
+
+// Recordings stored under more than one release, the kept release is marked
+pub fn print_duplicates(library: &LibraryDatabase, settings: &Settings) {
+    let groups = library
+        .retrieve_tracks()
+        .into_values()
+        .into_group_map_by(canonical_key)
+        .into_values()
+        .filter(|tracks| tracks.len() > 1)
+        .sorted_by_key(|tracks| normalize(&tracks[0].name))
+        .collect_vec();
+
+    for tracks in &groups {
+        let kept = tracks
+            .iter()
+            .reduce(|kept, track| {
+                if settings.release_preference.prefers(track, kept) {
+                    track
+                } else {
+                    kept
+                }
+            })
+            .unwrap();
+        println!(
+            "{} - {}",
+            tracks[0].artists.get(0).unwrap().name,
+            tracks[0].name
+        );
+        for track in tracks {
+            println!(
+                "  {} {} ({}, {})",
+                if track.id == kept.id { "*" } else { " " },
+                track.album.name,
+                track.album.album_type.as_deref().unwrap_or("unknown"),
+                track.album.release_date.as_deref().unwrap_or("no date")
+            );
+        }
+    }
+    println!(
+        "{} recordings are stored more than once, kept releases are marked with *",
+        groups.len()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::fixtures::track;
+
+    fn release(id: &str, isrc: &str, release_date: &str, album_type: &str) -> FullTrack {
+        let mut track = track(id, "Song", "Artist", id, 200000);
+        track
+            .external_ids
+            .insert(String::from("isrc"), isrc.to_string());
+        track.album.release_date = Some(release_date.to_string());
+        track.album.album_type = Some(album_type.to_string());
+        track
+    }
+
+    fn ids(tracks: &[FullTrack]) -> Vec<String> {
+        tracks
+            .iter()
+            .map(|track| track.id.as_ref().unwrap().id().to_string())
+            .collect_vec()
+    }
+
+    #[test]
+    fn isrc_decides_the_key() {
+        let original = release("original", "usabc1234567", "2010-01-01", "album");
+        let remaster = release("remaster", "USABC1234567", "2020-01-01", "album");
+        assert_eq!(canonical_key(&original), "isrc:USABC1234567");
+        assert_eq!(canonical_key(&original), canonical_key(&remaster));
+    }
+
+    #[test]
+    fn without_isrc_names_and_length_decide() {
+        let a = track("a", "Song (feat. Someone)", "The Artist", "One", 199500);
+        let b = track("b", "song", "the artist", "Two", 200900);
+        let c = track("c", "Song", "The Artist", "Three", 230000);
+        assert_eq!(canonical_key(&a), canonical_key(&b));
+        assert_ne!(canonical_key(&a), canonical_key(&c));
+    }
+
+    #[test]
+    fn kept_release_takes_the_first_place() {
+        let tracks = vec![
+            release("remaster", "USABC1234567", "2020-01-01", "album"),
+            track("other", "Other", "Artist", "Other", 180000),
+            release("original", "USABC1234567", "2010-01-01", "album"),
+        ];
+        assert_eq!(
+            ids(&dedupe_tracks(tracks.clone(), ReleasePreference::Original)),
+            ["original", "other"]
+        );
+        assert_eq!(
+            ids(&dedupe_tracks(tracks, ReleasePreference::Latest)),
+            ["remaster", "other"]
+        );
+    }
+
+    #[test]
+    fn album_preference_skips_singles() {
+        let tracks = vec![
+            release("single", "USABC1234567", "2009-01-01", "single"),
+            release("album", "USABC1234567", "2010-01-01", "album"),
+        ];
+        assert_eq!(
+            ids(&dedupe_tracks(tracks.clone(), ReleasePreference::Album)),
+            ["album"]
+        );
+        assert_eq!(
+            ids(&dedupe_tracks(tracks, ReleasePreference::Original)),
+            ["single"]
+        );
+    }
+}
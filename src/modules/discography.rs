@@ -0,0 +1,214 @@
+use clap::ValueEnum;
+use itertools::Itertools;
+use rspotify::{
+    model::{AlbumId, ArtistId, FullTrack, SimplifiedAlbum},
+    prelude::*,
+    AuthCodeSpotify,
+};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+
+use super::{
+    conversion::albums_to_tracks,
+    import::normalize,
+    releases::{artist_releases, ReleaseType},
+    retrieve::albums,
+    storage::LibraryDatabase,
+};
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum ReportFormat {
+    Table,
+    Json,
+}
+
+pub struct MissingRelease {
+    id: AlbumId<'static>,
+    name: String,
+    group: String,
+    release_date: String,
+}
+
+pub struct ArtistReport {
+    id: String,
+    name: String,
+    saved_tracks: usize,
+    num_releases: usize,
+    missing: Vec<MissingRelease>,
+}
+
+// Artists with at least min_tracks saved tracks, most saved first. Every artist on a track counts
+// so features count too
+fn library_artists(library: &LibraryDatabase, min_tracks: usize) -> Vec<(String, String, usize)> {
+    let mut names = HashMap::new();
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for track in library.retrieve_tracks().into_values() {
+        for artist in track.artists {
+            let Some(id) = artist.id else {
+                continue;
+            };
+            *counts.entry(id.id().to_string()).or_default() += 1;
+            names.insert(id.id().to_string(), artist.name);
+        }
+    }
+    counts
+        .into_iter()
+        .filter(|(_, count)| *count >= min_tracks)
+        .map(|(id, count)| (id.clone(), names[&id].clone(), count))
+        .sorted_by(|(_, a_name, a), (_, b_name, b)| b.cmp(a).then_with(|| a_name.cmp(b_name)))
+        .collect_vec()
+}
+
+// A release counts as owned when its album or one of its tracks is saved. Names are checked as
+// well, per artist, since Spotify lists every edition of an album under its own id
+fn owned_releases(library: &LibraryDatabase) -> (HashSet<String>, HashSet<(String, String)>) {
+    let mut ids = HashSet::new();
+    let mut names = HashSet::new();
+    for (id, album) in library.retrieve_albums() {
+        ids.insert(id);
+        for artist in album.artists.iter().filter_map(|artist| artist.id.as_ref()) {
+            names.insert((artist.id().to_string(), normalize(&album.name)));
+        }
+    }
+    for track in library.retrieve_tracks().into_values() {
+        if let Some(id) = &track.album.id {
+            ids.insert(id.id().to_string());
+        }
+        for artist in track.artists.iter().filter_map(|artist| artist.id.as_ref()) {
+            names.insert((artist.id().to_string(), normalize(&track.album.name)));
+        }
+    }
+    (ids, names)
+}
+
+fn release_group(release: &SimplifiedAlbum) -> Option<&str> {
+    release
+        .album_group
+        .as_deref()
+        .or(release.album_type.as_deref())
+}
+
+// Goes through each artist's releases and keeps the ones not in the library, oldest first. Only
+// the first edition of each name is kept
+pub async fn find_missing_releases(
+    spotify: &AuthCodeSpotify,
+    library: &LibraryDatabase,
+    min_tracks: usize,
+    release_types: &[ReleaseType],
+) -> Vec<ArtistReport> {
+    let groups = release_types
+        .iter()
+        .map(|release_type| release_type.group())
+        .collect_vec();
+    let (owned_ids, owned_names) = owned_releases(library);
+
+    let mut reports = Vec::new();
+    for (id, name, saved_tracks) in library_artists(library, min_tracks) {
+        let artist_id = ArtistId::from_id(id.as_str()).unwrap().clone_static();
+        let releases = artist_releases(spotify, library, artist_id)
+            .await
+            .into_iter()
+            .filter(|release| release_group(release).map_or(false, |group| groups.contains(&group)))
+            .sorted_by_key(|release| release.release_date.clone().unwrap_or_default())
+            .unique_by(|release| normalize(&release.name))
+            .collect_vec();
+
+        let missing = releases
+            .iter()
+            .filter(|release| {
+                let owned_id = release
+                    .id
+                    .as_ref()
+                    .map_or(false, |release_id| owned_ids.contains(release_id.id()));
+                !owned_id && !owned_names.contains(&(id.clone(), normalize(&release.name)))
+            })
+            .filter_map(|release| {
+                Some(MissingRelease {
+                    id: release.id.clone()?,
+                    name: release.name.clone(),
+                    group: release_group(release)?.to_string(),
+                    release_date: release.release_date.clone().unwrap_or_default(),
+                })
+            })
+            .collect_vec();
+        reports.push(ArtistReport {
+            id,
+            name,
+            saved_tracks,
+            num_releases: releases.len(),
+            missing,
+        });
+    }
+    reports
+}
+
+pub fn print_report(reports: &[ArtistReport], format: ReportFormat) {
+    match format {
+        ReportFormat::Table => {
+            for report in reports {
+                println!(
+                    "{} ({} saved tracks, {} of {} releases)",
+                    report.name,
+                    report.saved_tracks,
+                    report.num_releases - report.missing.len(),
+                    report.num_releases
+                );
+                for release in &report.missing {
+                    println!(
+                        "  {:<10}  {:<11}  {}",
+                        release.release_date, release.group, release.name
+                    );
+                }
+            }
+            let num_missing = reports
+                .iter()
+                .map(|report| report.missing.len())
+                .sum::<usize>();
+            println!(
+                "{} releases missing from {} artists",
+                num_missing,
+                reports.len()
+            );
+        }
+        ReportFormat::Json => {
+            let reports = reports
+                .iter()
+                .map(|report| {
+                    json!({
+                        "id": report.id,
+                        "name": report.name,
+                        "saved_tracks": report.saved_tracks,
+                        "releases": report.num_releases,
+                        "missing": report
+                            .missing
+                            .iter()
+                            .map(|release| json!({
+                                "id": release.id.id(),
+                                "name": release.name,
+                                "type": release.group,
+                                "release_date": release.release_date,
+                            }))
+                            .collect_vec(),
+                    })
+                })
+                .collect::<Vec<Value>>();
+            println!("{}", serde_json::to_string_pretty(&reports).unwrap());
+        }
+    }
+}
+
+// The tracks of every missing release, artist by artist, for going through in a playlist
+pub async fn missing_tracks(
+    spotify: &AuthCodeSpotify,
+    library: &LibraryDatabase,
+    reports: &[ArtistReport],
+) -> Vec<FullTrack> {
+    let album_ids = reports
+        .iter()
+        .flat_map(|report| report.missing.iter())
+        .map(|release| release.id.clone())
+        .unique_by(|id| id.id().to_string())
+        .collect_vec();
+    let albums = albums(spotify, album_ids).await;
+    albums_to_tracks(spotify, albums, Some(library.market())).await
+}
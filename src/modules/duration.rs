@@ -0,0 +1,263 @@
+use chrono::Duration;
+use rspotify::model::FullTrack;
+
+// Longer than any playlist can get, and small enough that the seconds never overflow
+const MAX_DURATION_SECS: i64 = 1000 * 3600;
+
+// Parses durations like "3h", "90m", "1h30m" or "45s", a bare number is taken as minutes
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let text = text.trim().to_lowercase();
+    let parts = if !text.is_empty() && text.chars().all(|c| c.is_ascii_digit()) {
+        format!("{}m", text)
+    } else {
+        text.clone()
+    };
+
+    let mut seconds: i64 = 0;
+    let mut number = String::new();
+    for c in parts.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let value = number
+            .parse::<i64>()
+            .map_err(|_| format!("Invalid duration {}", text))?;
+        number.clear();
+        let unit = match c {
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(format!("Unknown duration unit {} in {}", c, text)),
+        };
+        seconds = value
+            .checked_mul(unit)
+            .and_then(|value| seconds.checked_add(value))
+            .filter(|seconds| *seconds <= MAX_DURATION_SECS)
+            .ok_or_else(|| format!("Duration {} is too long", text))?;
+    }
+
+    if !number.is_empty() || seconds == 0 {
+        return Err(format!("Invalid duration {}", text));
+    }
+    Ok(Duration::seconds(seconds))
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let minutes = duration.num_minutes();
+    format!("{}h{:02}m", minutes / 60, minutes % 60)
+}
+
+// A playlist length to aim for, anything within the tolerance either side counts
+#[derive(Clone, Copy, Debug)]
+pub struct DurationTarget {
+    pub target: Duration,
+    pub tolerance: Duration,
+}
+
+impl DurationTarget {
+    pub fn is_reached(&self, total: Duration) -> bool {
+        total >= self.target - self.tolerance
+    }
+
+    pub fn allows(&self, total: Duration) -> bool {
+        total <= self.target + self.tolerance
+    }
+
+    // What's left of the target once some of it has been used
+    pub fn remaining(&self, used: Duration) -> DurationTarget {
+        DurationTarget {
+            target: (self.target - used).max(Duration::zero()),
+            tolerance: self.tolerance,
+        }
+    }
+}
+
+pub fn total_duration(tracks: &[FullTrack]) -> Duration {
+    tracks
+        .iter()
+        .fold(Duration::zero(), |total, track| total + track.duration)
+}
+
+// How many tracks a generator puts in a playlist
+#[derive(Clone, Copy, Debug)]
+pub enum TrackLimit {
+    Count(usize),
+    Duration(DurationTarget),
+}
+
+impl TrackLimit {
+    pub fn new(num_songs: usize, duration: Option<DurationTarget>) -> TrackLimit {
+        match duration {
+            Some(target) => TrackLimit::Duration(target),
+            None => TrackLimit::Count(num_songs),
+        }
+    }
+
+    // How many tracks a sampler has to rank before the limit is applied
+    pub fn max_tracks(&self) -> usize {
+        match self {
+            TrackLimit::Count(num_songs) => *num_songs,
+            TrackLimit::Duration(_) => usize::MAX,
+        }
+    }
+
+    fn is_reached_by(&self, num_tracks: usize, total: Duration) -> bool {
+        match self {
+            TrackLimit::Count(num_songs) => num_tracks >= *num_songs,
+            TrackLimit::Duration(target) => target.is_reached(total),
+        }
+    }
+
+    pub fn is_reached(&self, tracks: &[FullTrack]) -> bool {
+        self.is_reached_by(tracks.len(), total_duration(tracks))
+    }
+
+    // Adds candidates in order until the limit is reached. Tracks that would overshoot the
+    // duration are skipped so a shorter one further down can still fit
+    pub fn fill(
+        &self,
+        chosen: &mut Vec<FullTrack>,
+        candidates: impl IntoIterator<Item = FullTrack>,
+    ) {
+        let mut total = total_duration(chosen);
+        for track in candidates {
+            if self.is_reached_by(chosen.len(), total) {
+                break;
+            }
+            if let TrackLimit::Duration(target) = self {
+                if !target.allows(total + track.duration) {
+                    continue;
+                }
+            }
+            total = total + track.duration;
+            chosen.push(track);
+        }
+    }
+
+    // How many tracks from the start of a playlist fit in the limit, without skipping any
+    pub fn leading_tracks(&self, tracks: &[FullTrack]) -> usize {
+        match self {
+            TrackLimit::Count(num_songs) => *num_songs,
+            TrackLimit::Duration(target) => {
+                let mut total = Duration::zero();
+                tracks
+                    .iter()
+                    .take_while(|track| {
+                        total = total + track.duration;
+                        target.allows(total)
+                    })
+                    .count()
+            }
+        }
+    }
+
+    pub fn apply(&self, tracks: Vec<FullTrack>) -> Vec<FullTrack> {
+        let mut chosen = Vec::new();
+        self.fill(&mut chosen, tracks);
+        chosen
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::fixtures::track;
+
+    fn minutes(tracks: &[FullTrack]) -> i64 {
+        total_duration(tracks).num_minutes()
+    }
+
+    #[test]
+    fn parses_units_and_bare_minutes() {
+        assert_eq!(parse_duration("90"), Ok(Duration::minutes(90)));
+        assert_eq!(parse_duration("3h"), Ok(Duration::hours(3)));
+        assert_eq!(
+            parse_duration("1h30m"),
+            Ok(Duration::hours(1) + Duration::minutes(30))
+        );
+        assert_eq!(parse_duration(" 45S "), Ok(Duration::seconds(45)));
+    }
+
+    #[test]
+    fn rejects_bad_durations() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("1h30").is_err());
+        assert!(parse_duration("2d").is_err());
+        assert!(parse_duration("0m").is_err());
+        assert!(parse_duration("0").is_err());
+        assert!(parse_duration("-5").is_err());
+        assert!(parse_duration("-5m").is_err());
+        assert!(parse_duration("1.5h").is_err());
+    }
+
+    #[test]
+    fn rejects_durations_too_long_to_hold() {
+        assert_eq!(parse_duration("1000h"), Ok(Duration::hours(1000)));
+        assert!(parse_duration("1000h1s").is_err());
+        assert!(parse_duration("9999999999999999h").is_err());
+        assert!(parse_duration("99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn formats_hours_and_minutes() {
+        assert_eq!(format_duration(Duration::minutes(95)), "1h35m");
+        assert_eq!(format_duration(Duration::minutes(5)), "0h05m");
+    }
+
+    #[test]
+    fn fill_skips_tracks_that_overshoot() {
+        let limit = TrackLimit::Duration(DurationTarget {
+            target: Duration::minutes(10),
+            tolerance: Duration::minutes(1),
+        });
+        let tracks = vec![
+            track("a", "A", "Artist", "Album", 6 * 60000),
+            track("b", "B", "Artist", "Album", 6 * 60000),
+            track("c", "C", "Artist", "Album", 4 * 60000),
+            track("d", "D", "Artist", "Album", 3 * 60000),
+        ];
+        let chosen = limit.apply(tracks);
+        assert_eq!(
+            chosen
+                .iter()
+                .map(|track| track.name.as_str())
+                .collect::<Vec<_>>(),
+            ["A", "C"]
+        );
+        assert_eq!(minutes(&chosen), 10);
+    }
+
+    #[test]
+    fn leading_tracks_stop_at_the_first_overshoot() {
+        let limit = TrackLimit::Duration(DurationTarget {
+            target: Duration::minutes(10),
+            tolerance: Duration::zero(),
+        });
+        let tracks = vec![
+            track("a", "A", "Artist", "Album", 6 * 60000),
+            track("b", "B", "Artist", "Album", 6 * 60000),
+            track("c", "C", "Artist", "Album", 60000),
+        ];
+        assert_eq!(limit.leading_tracks(&tracks), 1);
+        assert_eq!(TrackLimit::Count(2).leading_tracks(&tracks), 2);
+    }
+
+    #[test]
+    fn remaining_never_goes_negative() {
+        let target = DurationTarget {
+            target: Duration::minutes(30),
+            tolerance: Duration::minutes(2),
+        };
+        assert_eq!(
+            target.remaining(Duration::minutes(20)).target,
+            Duration::minutes(10)
+        );
+        assert_eq!(
+            target.remaining(Duration::minutes(40)).target,
+            Duration::zero()
+        );
+    }
+}
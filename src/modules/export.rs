@@ -0,0 +1,476 @@
+use clap::ValueEnum;
+use itertools::Itertools;
+use rspotify::{
+    model::{FullAlbum, FullEpisode, FullTrack, PlayableItem, PlaylistId, SimplifiedTrack},
+    prelude::*,
+    AuthCodeSpotify,
+};
+use serde_json::{json, Map, Value};
+use std::fs;
+
+use super::{playlists::get_playlist_items, storage::LibraryDatabase};
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum ExportSource {
+    Tracks,
+    Albums,
+    Liked,
+    Local,
+    Episodes,
+    Playlist,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum ExportFormat {
+    Csv,
+    Jsonl,
+    M3u8,
+    Xspf,
+}
+
+// Columns used by the csv and jsonl formats, m3u8 and xspf have fixed fields
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum ExportColumn {
+    Id,
+    Uri,
+    Name,
+    Artists,
+    Album,
+    Duration,
+    DiscNumber,
+    TrackNumber,
+    ReleaseDate,
+    Isrc,
+    Popularity,
+}
+
+impl ExportColumn {
+    fn header(&self) -> &'static str {
+        match self {
+            ExportColumn::Id => "id",
+            ExportColumn::Uri => "uri",
+            ExportColumn::Name => "name",
+            ExportColumn::Artists => "artists",
+            ExportColumn::Album => "album",
+            ExportColumn::Duration => "duration_ms",
+            ExportColumn::DiscNumber => "disc_number",
+            ExportColumn::TrackNumber => "track_number",
+            ExportColumn::ReleaseDate => "release_date",
+            ExportColumn::Isrc => "isrc",
+            ExportColumn::Popularity => "popularity",
+        }
+    }
+}
+
+// A flattened track that every export format can be written from
+pub struct ExportItem {
+    pub id: String,
+    pub uri: String,
+    pub name: String,
+    pub artists: Vec<String>,
+    pub album: String,
+    pub duration_ms: i64,
+    pub disc_number: i32,
+    pub track_number: u32,
+    pub release_date: String,
+    pub isrc: String,
+    pub popularity: Option<u32>,
+}
+
+impl ExportItem {
+    // Local files have no id and the api doesn't give their uri, so both are left empty
+    pub fn from_track(track: &FullTrack) -> ExportItem {
+        ExportItem {
+            id: track
+                .id
+                .as_ref()
+                .filter(|_| !track.is_local)
+                .map(|id| id.id().to_string())
+                .unwrap_or_default(),
+            uri: track
+                .id
+                .as_ref()
+                .filter(|_| !track.is_local)
+                .map(|id| id.uri())
+                .unwrap_or_default(),
+            name: track.name.clone(),
+            artists: track
+                .artists
+                .iter()
+                .map(|artist| artist.name.clone())
+                .collect_vec(),
+            album: track.album.name.clone(),
+            duration_ms: track.duration.num_milliseconds(),
+            disc_number: track.disc_number,
+            track_number: track.track_number,
+            release_date: track.album.release_date.clone().unwrap_or_default(),
+            isrc: track.external_ids.get("isrc").cloned().unwrap_or_default(),
+            popularity: Some(track.popularity),
+        }
+    }
+
+    // Album listings only hold simplified tracks, so isrc and popularity aren't known
+    pub fn from_album_track(album: &FullAlbum, track: &SimplifiedTrack) -> ExportItem {
+        ExportItem {
+            id: track
+                .id
+                .as_ref()
+                .map(|id| id.id().to_string())
+                .unwrap_or_default(),
+            uri: track.id.as_ref().map(|id| id.uri()).unwrap_or_default(),
+            name: track.name.clone(),
+            artists: track
+                .artists
+                .iter()
+                .map(|artist| artist.name.clone())
+                .collect_vec(),
+            album: album.name.clone(),
+            duration_ms: track.duration.num_milliseconds(),
+            disc_number: track.disc_number,
+            track_number: track.track_number,
+            release_date: album.release_date.clone(),
+            isrc: String::new(),
+            popularity: None,
+        }
+    }
+
+    // The show stands in for the album and its publisher for the artist
+    pub fn from_episode(episode: &FullEpisode) -> ExportItem {
+        ExportItem {
+            id: episode.id.id().to_string(),
+            uri: episode.id.uri(),
+            name: episode.name.clone(),
+            artists: vec![episode.show.publisher.clone()],
+            album: episode.show.name.clone(),
+            duration_ms: episode.duration.num_milliseconds(),
+            disc_number: 0,
+            track_number: 0,
+            release_date: episode.release_date.clone(),
+            isrc: String::new(),
+            popularity: None,
+        }
+    }
+
+    pub fn from_item(item: &PlayableItem) -> ExportItem {
+        match item {
+            PlayableItem::Track(track) => ExportItem::from_track(track),
+            PlayableItem::Episode(episode) => ExportItem::from_episode(episode),
+        }
+    }
+
+    fn value(&self, column: ExportColumn) -> Value {
+        match column {
+            ExportColumn::Id => json!(self.id),
+            ExportColumn::Uri => json!(self.uri),
+            ExportColumn::Name => json!(self.name),
+            ExportColumn::Artists => json!(self.artists),
+            ExportColumn::Album => json!(self.album),
+            ExportColumn::Duration => json!(self.duration_ms),
+            ExportColumn::DiscNumber => json!(self.disc_number),
+            ExportColumn::TrackNumber => json!(self.track_number),
+            ExportColumn::ReleaseDate => json!(self.release_date),
+            ExportColumn::Isrc => json!(self.isrc),
+            ExportColumn::Popularity => json!(self.popularity),
+        }
+    }
+
+    fn text(&self, column: ExportColumn) -> String {
+        match self.value(column) {
+            Value::String(text) => text,
+            Value::Null => String::new(),
+            Value::Array(values) => values.iter().filter_map(|value| value.as_str()).join("; "),
+            value => value.to_string(),
+        }
+    }
+}
+
+pub fn tracks_to_items(tracks: &[FullTrack]) -> Vec<ExportItem> {
+    tracks.iter().map(ExportItem::from_track).collect_vec()
+}
+
+pub fn albums_to_items(albums: &[FullAlbum]) -> Vec<ExportItem> {
+    albums
+        .iter()
+        .flat_map(|album| {
+            album
+                .tracks
+                .items
+                .iter()
+                .map(move |track| ExportItem::from_album_track(album, track))
+        })
+        .collect_vec()
+}
+
+// Library maps are unordered, so library exports are sorted by artist, album and track number
+fn sort_items(items: &mut [ExportItem]) {
+    items.sort_by(|a, b| {
+        (&a.artists, &a.album, a.disc_number, a.track_number).cmp(&(
+            &b.artists,
+            &b.album,
+            b.disc_number,
+            b.track_number,
+        ))
+    });
+}
+
+pub async fn export(
+    spotify: &AuthCodeSpotify,
+    library: &LibraryDatabase,
+    source: ExportSource,
+    playlist_id: Option<&str>,
+    format: ExportFormat,
+    columns: &[ExportColumn],
+    output: Option<&str>,
+) -> Result<(), String> {
+    let (title, mut items) = match source {
+        ExportSource::Tracks => (
+            String::from("Tracks"),
+            tracks_to_items(&library.retrieve_tracks().into_values().collect_vec()),
+        ),
+        ExportSource::Albums => (
+            String::from("Albums"),
+            albums_to_items(&library.retrieve_albums().into_values().collect_vec()),
+        ),
+        ExportSource::Liked => (
+            String::from("Liked"),
+            tracks_to_items(&library.retrieve_liked().into_values().collect_vec()),
+        ),
+        ExportSource::Local => (
+            String::from("Local files"),
+            tracks_to_items(&library.retrieve_local().into_values().collect_vec()),
+        ),
+        ExportSource::Episodes => (
+            String::from("Episodes"),
+            library
+                .retrieve_episodes()
+                .values()
+                .map(ExportItem::from_episode)
+                .collect_vec(),
+        ),
+        ExportSource::Playlist => {
+            let playlist_id = playlist_id
+                .ok_or_else(|| String::from("A playlist is needed to export a playlist"))?;
+            let playlist_id = PlaylistId::from_id(playlist_id)
+                .map_err(|_| format!("Invalid playlist id {}", playlist_id))?;
+            let playlist = spotify
+                .playlist(playlist_id.clone(), None, None)
+                .await
+                .unwrap();
+            (
+                playlist.name,
+                get_playlist_items(spotify, &playlist_id)
+                    .await
+                    .iter()
+                    .map(ExportItem::from_item)
+                    .collect_vec(),
+            )
+        }
+    };
+
+    if source != ExportSource::Playlist {
+        sort_items(&mut items);
+    }
+    write_export(&items, format, columns, &title, output);
+    Ok(())
+}
+
+pub fn export_items(
+    items: &[ExportItem],
+    format: ExportFormat,
+    columns: &[ExportColumn],
+    title: &str,
+) -> String {
+    match format {
+        ExportFormat::Csv => to_csv(items, columns),
+        ExportFormat::Jsonl => to_jsonl(items, columns),
+        ExportFormat::M3u8 => to_m3u8(items, title),
+        ExportFormat::Xspf => to_xspf(items, title),
+    }
+}
+
+// Writes to the given path or prints the export when there isn't one
+pub fn write_export(
+    items: &[ExportItem],
+    format: ExportFormat,
+    columns: &[ExportColumn],
+    title: &str,
+    output: Option<&str>,
+) {
+    let exported = export_items(items, format, columns, title);
+    match output {
+        Some(path) => {
+            fs::write(path, exported).unwrap();
+            println!("Exported {} tracks to {}", items.len(), path);
+        }
+        None => print!("{}", exported),
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn to_csv(items: &[ExportItem], columns: &[ExportColumn]) -> String {
+    let mut lines = vec![columns.iter().map(|column| column.header()).join(",")];
+    for item in items {
+        lines.push(
+            columns
+                .iter()
+                .map(|column| csv_field(&item.text(*column)))
+                .join(","),
+        );
+    }
+    lines.join("\n") + "\n"
+}
+
+fn to_jsonl(items: &[ExportItem], columns: &[ExportColumn]) -> String {
+    let mut exported = String::new();
+    for item in items {
+        let mut object = Map::new();
+        for column in columns {
+            object.insert(column.header().to_string(), item.value(*column));
+        }
+        exported += &Value::Object(object).to_string();
+        exported += "\n";
+    }
+    exported
+}
+
+fn to_m3u8(items: &[ExportItem], title: &str) -> String {
+    let mut exported = format!("#EXTM3U\n#PLAYLIST:{}\n", title);
+    // Local files have no uri to point at, so they're left out
+    for item in items.iter().filter(|item| !item.uri.is_empty()) {
+        exported += &format!(
+            "#EXTINF:{},{} - {}\n{}\n",
+            item.duration_ms / 1000,
+            item.artists.join(", "),
+            item.name,
+            item.uri
+        );
+    }
+    exported
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn to_xspf(items: &[ExportItem], title: &str) -> String {
+    let mut exported = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    exported += "<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n";
+    exported += &format!("  <title>{}</title>\n  <trackList>\n", xml_escape(title));
+    for item in items {
+        exported += "    <track>\n";
+        // Local files have no uri to point at
+        if !item.uri.is_empty() {
+            exported += &format!("      <location>{}</location>\n", xml_escape(&item.uri));
+            exported += &format!("      <identifier>{}</identifier>\n", xml_escape(&item.uri));
+        }
+        exported += &format!("      <title>{}</title>\n", xml_escape(&item.name));
+        exported += &format!(
+            "      <creator>{}</creator>\n",
+            xml_escape(&item.artists.join(", "))
+        );
+        exported += &format!("      <album>{}</album>\n", xml_escape(&item.album));
+        exported += &format!("      <trackNum>{}</trackNum>\n", item.track_number);
+        exported += &format!("      <duration>{}</duration>\n", item.duration_ms);
+        exported += "    </track>\n";
+    }
+    exported += "  </trackList>\n</playlist>\n";
+    exported
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(uri: &str, name: &str, artists: &[&str], album: &str) -> ExportItem {
+        ExportItem {
+            id: uri.rsplit(':').next().unwrap_or_default().to_string(),
+            uri: uri.to_string(),
+            name: name.to_string(),
+            artists: artists
+                .iter()
+                .map(|artist| artist.to_string())
+                .collect_vec(),
+            album: album.to_string(),
+            duration_ms: 200000,
+            disc_number: 1,
+            track_number: 3,
+            release_date: String::from("2020-01-01"),
+            isrc: String::new(),
+            popularity: None,
+        }
+    }
+
+    #[test]
+    fn csv_quotes_only_when_needed() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a, b"), "\"a, b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+    }
+
+    #[test]
+    fn csv_writes_the_chosen_columns_in_order() {
+        let items = [item(
+            "spotify:track:abc",
+            "Song, Part 1",
+            &["A", "B"],
+            "Album",
+        )];
+        let exported = to_csv(
+            &items,
+            &[
+                ExportColumn::Artists,
+                ExportColumn::Name,
+                ExportColumn::Popularity,
+            ],
+        );
+        assert_eq!(
+            exported,
+            "artists,name,popularity\nA; B,\"Song, Part 1\",\n"
+        );
+    }
+
+    #[test]
+    fn jsonl_keeps_types_and_only_the_chosen_columns() {
+        let items = [item("spotify:track:abc", "Song", &["A", "B"], "Album")];
+        let exported = to_jsonl(&items, &[ExportColumn::Artists, ExportColumn::TrackNumber]);
+        let value = serde_json::from_str::<Value>(exported.trim()).unwrap();
+        assert_eq!(value, json!({"artists": ["A", "B"], "track_number": 3}));
+    }
+
+    #[test]
+    fn xml_escapes_markup() {
+        assert_eq!(
+            xml_escape("Rock & <Roll> \"'"),
+            "Rock &amp; &lt;Roll&gt; &quot;&apos;"
+        );
+    }
+
+    #[test]
+    fn local_files_have_no_location() {
+        let items = [
+            item("spotify:track:abc", "Song", &["A"], "Album"),
+            item("", "Local", &["B"], "Album"),
+        ];
+        let m3u8 = to_m3u8(&items, "Title");
+        assert_eq!(
+            m3u8,
+            "#EXTM3U\n#PLAYLIST:Title\n#EXTINF:200,A - Song\nspotify:track:abc\n"
+        );
+
+        let xspf = to_xspf(&items, "Title");
+        assert_eq!(xspf.matches("<location>").count(), 1);
+        assert_eq!(xspf.matches("<track>").count(), 2);
+    }
+}
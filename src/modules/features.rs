@@ -0,0 +1,276 @@
+use clap::ValueEnum;
+use rspotify::{
+    model::{AudioFeatures, FullTrack},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// The audio features we keep for each track, stored in the library by track id
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TrackFeatures {
+    pub tempo: f32,
+    pub energy: f32,
+    pub danceability: f32,
+    pub valence: f32,
+    // Pitch class from 0 (C) to 11 (B), -1 when no key was detected
+    pub key: i32,
+    // 1 for major, 0 for minor
+    pub mode: i32,
+    pub acousticness: f32,
+}
+
+impl TrackFeatures {
+    pub fn from_audio_features(features: &AudioFeatures) -> TrackFeatures {
+        TrackFeatures {
+            tempo: features.tempo,
+            energy: features.energy,
+            danceability: features.danceability,
+            valence: features.valence,
+            key: features.key,
+            mode: features.mode as i32,
+            acousticness: features.acousticness,
+        }
+    }
+
+    pub fn get(&self, field: FeatureField) -> f64 {
+        match field {
+            FeatureField::Tempo => self.tempo as f64,
+            FeatureField::Energy => self.energy as f64,
+            FeatureField::Danceability => self.danceability as f64,
+            FeatureField::Valence => self.valence as f64,
+            FeatureField::Key => self.key as f64,
+            FeatureField::Mode => self.mode as f64,
+            FeatureField::Acousticness => self.acousticness as f64,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum FeatureField {
+    /// Beats per minute
+    Tempo,
+    /// Intensity from 0 to 1
+    Energy,
+    /// How suitable for dancing from 0 to 1
+    Danceability,
+    /// How positive the track sounds from 0 to 1
+    Valence,
+    /// Pitch class from 0 (C) to 11 (B)
+    Key,
+    /// 1 for major, 0 for minor
+    Mode,
+    /// Confidence the track is acoustic from 0 to 1
+    Acousticness,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Comparison {
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+    Equal,
+    NotEqual,
+    // Inclusive on both ends
+    Between(f64, f64),
+}
+
+#[derive(Clone, Debug)]
+struct Condition {
+    field: FeatureField,
+    comparison: Comparison,
+    value: f64,
+}
+
+impl Condition {
+    fn allows(&self, features: &TrackFeatures) -> bool {
+        let value = features.get(self.field);
+        match self.comparison {
+            Comparison::Greater => value > self.value,
+            Comparison::GreaterOrEqual => value >= self.value,
+            Comparison::Less => value < self.value,
+            Comparison::LessOrEqual => value <= self.value,
+            Comparison::Equal => (value - self.value).abs() < f64::EPSILON,
+            Comparison::NotEqual => (value - self.value).abs() >= f64::EPSILON,
+            Comparison::Between(low, high) => low <= value && value <= high,
+        }
+    }
+}
+
+// Conditions on audio features that all have to hold, e.g. "energy > 0.7 and tempo 120..130"
+#[derive(Clone, Debug)]
+pub struct FeatureFilter {
+    conditions: Vec<Condition>,
+}
+
+impl FeatureFilter {
+    // Tracks without stored features never pass
+    pub fn allows(&self, features: Option<&TrackFeatures>) -> bool {
+        match features {
+            Some(features) => self
+                .conditions
+                .iter()
+                .all(|condition| condition.allows(features)),
+            None => false,
+        }
+    }
+}
+
+// Longer operators first so ">=" isn't read as ">"
+static OPERATORS: [(&str, Comparison); 7] = [
+    (">=", Comparison::GreaterOrEqual),
+    ("<=", Comparison::LessOrEqual),
+    ("!=", Comparison::NotEqual),
+    ("==", Comparison::Equal),
+    (">", Comparison::Greater),
+    ("<", Comparison::Less),
+    ("=", Comparison::Equal),
+];
+
+fn parse_number(text: &str) -> Result<f64, String> {
+    text.trim()
+        .parse::<f64>()
+        .map_err(|_| format!("Invalid number {}", text.trim()))
+}
+
+fn parse_condition(text: &str) -> Result<Condition, String> {
+    let text = text.trim();
+    let field_end = text
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(text.len());
+    let field = FeatureField::from_str(&text[..field_end], true)
+        .map_err(|_| format!("Unknown audio feature {}", &text[..field_end]))?;
+    let rest = text[field_end..].trim();
+
+    if let Some((low, high)) = rest.split_once("..") {
+        let (low, high) = (parse_number(low)?, parse_number(high)?);
+        return Ok(Condition {
+            field,
+            comparison: Comparison::Between(low.min(high), low.max(high)),
+            value: low,
+        });
+    }
+
+    for (operator, comparison) in OPERATORS {
+        if let Some(value) = rest.strip_prefix(operator) {
+            return Ok(Condition {
+                field,
+                comparison,
+                value: parse_number(value)?,
+            });
+        }
+    }
+    Err(format!("Missing comparison in {}", text))
+}
+
+// Conditions are joined with "and", each one is a comparison like "energy > 0.7" or an inclusive
+// range like "tempo 120..130"
+pub fn parse_feature_filter(text: &str) -> Result<FeatureFilter, String> {
+    let conditions = text
+        .to_lowercase()
+        .split(" and ")
+        .map(parse_condition)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(FeatureFilter { conditions })
+}
+
+// How a smart playlist or search narrows down and sorts tracks by their audio features
+#[derive(Clone, Debug, Default)]
+pub struct FeatureQuery {
+    pub filter: Option<FeatureFilter>,
+    pub sort_by: Option<FeatureField>,
+    pub descending: bool,
+}
+
+impl FeatureQuery {
+    pub fn is_empty(&self) -> bool {
+        self.filter.is_none() && self.sort_by.is_none()
+    }
+
+    // Tracks without features go last when sorting
+    pub fn apply(
+        &self,
+        tracks: Vec<FullTrack>,
+        features: &HashMap<String, TrackFeatures>,
+    ) -> Vec<FullTrack> {
+        let track_features = |track: &FullTrack| features.get(track.id.as_ref().unwrap().id());
+        let mut tracks = match &self.filter {
+            Some(filter) => tracks
+                .into_iter()
+                .filter(|track| filter.allows(track_features(track)))
+                .collect(),
+            None => tracks,
+        };
+
+        if let Some(field) = self.sort_by {
+            tracks.sort_by(|a, b| {
+                let (a, b) = (
+                    track_features(a).map(|features| features.get(field)),
+                    track_features(b).map(|features| features.get(field)),
+                );
+                match (a, b) {
+                    (Some(a), Some(b)) if self.descending => b.partial_cmp(&a).unwrap(),
+                    (Some(a), Some(b)) => a.partial_cmp(&b).unwrap(),
+                    (a, b) => b.is_some().cmp(&a.is_some()),
+                }
+            });
+        }
+        tracks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn features(tempo: f32, energy: f32, key: i32) -> TrackFeatures {
+        TrackFeatures {
+            tempo,
+            energy,
+            danceability: 0.5,
+            valence: 0.5,
+            key,
+            mode: 1,
+            acousticness: 0.1,
+        }
+    }
+
+    #[test]
+    fn parses_comparisons_and_ranges() {
+        let filter = parse_feature_filter("Energy >= 0.7 and tempo 120..130").unwrap();
+        assert!(filter.allows(Some(&features(125.0, 0.8, 0))));
+        assert!(filter.allows(Some(&features(130.0, 0.9, 0))));
+        assert!(!filter.allows(Some(&features(131.0, 0.9, 0))));
+        assert!(!filter.allows(Some(&features(125.0, 0.69, 0))));
+    }
+
+    #[test]
+    fn longer_operators_win() {
+        let filter = parse_feature_filter("key != 5").unwrap();
+        assert!(filter.allows(Some(&features(120.0, 0.5, 4))));
+        assert!(!filter.allows(Some(&features(120.0, 0.5, 5))));
+
+        let filter = parse_feature_filter("key=5").unwrap();
+        assert!(filter.allows(Some(&features(120.0, 0.5, 5))));
+    }
+
+    #[test]
+    fn reversed_range_is_flipped() {
+        let filter = parse_feature_filter("tempo 130..120").unwrap();
+        assert!(filter.allows(Some(&features(125.0, 0.5, 0))));
+    }
+
+    #[test]
+    fn rejects_bad_filters() {
+        assert!(parse_feature_filter("loudness > 3").is_err());
+        assert!(parse_feature_filter("energy > high").is_err());
+        assert!(parse_feature_filter("energy 0.5").is_err());
+    }
+
+    #[test]
+    fn tracks_without_features_fail() {
+        let filter = parse_feature_filter("energy > 0").unwrap();
+        assert!(!filter.allows(None));
+    }
+}
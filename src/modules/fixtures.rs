@@ -0,0 +1,41 @@
+use rspotify::model::FullTrack;
+use serde_json::json;
+
+// A track the way the api returns one, for tests. Anything else a test needs is set on the result
+pub fn track(id: &str, name: &str, artist: &str, album: &str, duration_ms: i64) -> FullTrack {
+    let artist = json!({
+        "external_urls": {},
+        "href": null,
+        "id": format!("{}Artist", artist.replace(' ', "")),
+        "name": artist,
+    });
+    serde_json::from_value(json!({
+        "album": {
+            "album_type": "album",
+            "artists": [artist],
+            "available_markets": [],
+            "external_urls": {},
+            "href": null,
+            "id": format!("{}Album", album.replace(' ', "")),
+            "images": [],
+            "name": album,
+            "release_date": "2020-01-01",
+            "release_date_precision": "day",
+        },
+        "artists": [artist],
+        "available_markets": [],
+        "disc_number": 1,
+        "duration_ms": duration_ms,
+        "explicit": false,
+        "external_ids": {},
+        "external_urls": {},
+        "href": null,
+        "id": id,
+        "is_local": false,
+        "name": name,
+        "popularity": 50,
+        "preview_url": null,
+        "track_number": 1,
+    }))
+    .unwrap()
+}
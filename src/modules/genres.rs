@@ -0,0 +1,131 @@
+use itertools::Itertools;
+use rspotify::model::FullTrack;
+use std::collections::HashMap;
+
+use super::{settings::Settings, storage::LibraryDatabase};
+
+// Top level genres and the words that put a Spotify genre under them. Checked in order so
+// "pop punk" ends up in punk and "indie rock" in rock
+static TOP_LEVEL_GENRES: [(&str, &[&str]); 15] = [
+    ("Hip Hop", &["hip hop", "rap", "trap", "drill", "grime"]),
+    ("R&B", &["r&b", "soul", "funk"]),
+    ("Metal", &["metal", "deathcore", "grindcore"]),
+    ("Punk", &["punk", "hardcore", "emo"]),
+    ("Rock", &["rock", "grunge", "shoegaze"]),
+    (
+        "Electronic",
+        &[
+            "house",
+            "techno",
+            "edm",
+            "electro",
+            "trance",
+            "dubstep",
+            "drum and bass",
+            "ambient",
+            "idm",
+        ],
+    ),
+    ("Jazz", &["jazz", "bebop", "swing"]),
+    ("Classical", &["classical", "orchestra", "baroque", "opera"]),
+    ("Country", &["country", "bluegrass", "americana"]),
+    ("Folk", &["folk", "singer-songwriter"]),
+    (
+        "Latin",
+        &["latin", "reggaeton", "salsa", "bachata", "cumbia"],
+    ),
+    ("Reggae", &["reggae", "dancehall", "ska"]),
+    ("Blues", &["blues"]),
+    ("Pop", &["pop"]),
+    ("Indie", &["indie"]),
+];
+
+// Looks the genre up in the settings first, then the built in table. Mapping a genre to an empty
+// name drops it, and mapping a top level genre onto another merges the two
+pub fn top_level_genre(genre: &str, genre_map: &HashMap<String, String>) -> Option<String> {
+    let genre = genre.to_lowercase();
+    let top_level = match genre_map.get(&genre) {
+        Some(top_level) => top_level.clone(),
+        None => TOP_LEVEL_GENRES
+            .iter()
+            .find(|(_, words)| words.iter().any(|word| genre.contains(word)))
+            .map(|(top_level, _)| top_level.to_string())?,
+    };
+    let top_level = match genre_map.get(&top_level.to_lowercase()) {
+        Some(merged) => merged.clone(),
+        None => top_level,
+    };
+
+    if top_level.is_empty() {
+        None
+    } else {
+        Some(top_level)
+    }
+}
+
+// The top level genre most of the track's genres fall under, the earlier genre wins ties since
+// Spotify lists an artist's main genres first
+fn primary_genre(genres: &[String], genre_map: &HashMap<String, String>) -> Option<String> {
+    let top_levels = genres
+        .iter()
+        .filter_map(|genre| top_level_genre(genre, genre_map))
+        .collect_vec();
+    let counts = top_levels.iter().counts();
+    top_levels
+        .iter()
+        .rev()
+        .max_by_key(|top_level| counts[top_level])
+        .cloned()
+}
+
+// Library tracks under each top level genre with the most recently added first. Every track goes
+// under one genre so the playlists don't overlap
+pub fn split_by_genre(
+    library: &LibraryDatabase,
+    settings: &Settings,
+) -> HashMap<String, Vec<FullTrack>> {
+    let tracks = library.retrieve_tracks();
+    let added = library.retrieve_added();
+    let mut split: HashMap<String, Vec<FullTrack>> = HashMap::new();
+    for (track_id, genres) in library
+        .track_genres()
+        .into_iter()
+        .sorted_by(|(a, _), (b, _)| a.cmp(b))
+        .sorted_by_key(|(id, _)| std::cmp::Reverse(added.get(id).copied()))
+    {
+        let (Some(top_level), Some(track)) = (
+            primary_genre(&genres, &settings.genre_map),
+            tracks.get(&track_id),
+        ) else {
+            continue;
+        };
+        split.entry(top_level).or_default().push(track.clone());
+    }
+    split
+}
+
+// Shows how the library splits up and the biggest genres that aren't mapped to anything, which
+// are the ones worth adding to the mapping
+pub fn print_genres(library: &LibraryDatabase, settings: &Settings) {
+    let num_tracks = library.retrieve_tracks().len();
+    let split = split_by_genre(library, settings);
+    for (top_level, tracks) in split.iter().sorted_by_key(|(_, tracks)| tracks.len()).rev() {
+        println!("{:>6}  {}", tracks.len(), top_level);
+    }
+    let num_split = split.values().map(|tracks| tracks.len()).sum::<usize>();
+    println!("{:>6}  No genre", num_tracks - num_split);
+
+    let unmapped = library
+        .compile_genres()
+        .into_iter()
+        .filter(|(genre, _)| top_level_genre(genre, &settings.genre_map).is_none())
+        .sorted_by_key(|(_, track_ids)| std::cmp::Reverse(track_ids.len()))
+        .take(20)
+        .collect_vec();
+    if !unmapped.is_empty() {
+        println!("\nUnmapped genres:");
+        for (genre, track_ids) in unmapped {
+            println!("{:>6}  {}", track_ids.len(), genre);
+        }
+    }
+}
@@ -0,0 +1,396 @@
+use chrono::{DateTime, TimeZone, Utc};
+use rspotify::{model::PlayHistory, prelude::*, AuthCodeSpotify};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs, time::Duration};
+
+use super::{retrieve::recently_played, storage::LibraryDatabase};
+
+static RECENTLY_PLAYED_CURSOR: &str = "recently_played";
+
+// Spotify only counts a stream once 30 seconds have been played
+static MIN_PLAY_MS: i64 = 30000;
+
+// A single listen, keyed in the history store by play_key
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Play {
+    pub played_at: DateTime<Utc>,
+    pub track_id: String,
+    pub ms_played: i64,
+    pub skipped: bool,
+    pub track_name: Option<String>,
+    pub artist_name: Option<String>,
+}
+
+impl Play {
+    pub fn key(&self) -> String {
+        play_key(&self.track_id, &self.played_at)
+    }
+
+    // The endpoint only lists plays that counted as a stream, so they're treated as full listens
+    fn from_history(play: PlayHistory) -> Option<Play> {
+        let track_id = play.track.id.as_ref()?.id().to_string();
+        Some(Play {
+            played_at: play.played_at,
+            track_id,
+            ms_played: play.track.duration.num_milliseconds(),
+            skipped: false,
+            track_name: Some(play.track.name.clone()),
+            artist_name: play.track.artists.get(0).map(|artist| artist.name.clone()),
+        })
+    }
+}
+
+pub fn play_key(track_id: &str, played_at: &DateTime<Utc>) -> String {
+    format!("{}|{}", played_at.timestamp(), track_id)
+}
+
+// The fields we use from the entries in Streaming_History_Audio_*.json
+#[derive(Deserialize)]
+struct StreamingRecord {
+    ts: DateTime<Utc>,
+    ms_played: i64,
+    spotify_track_uri: Option<String>,
+    master_metadata_track_name: Option<String>,
+    master_metadata_album_artist_name: Option<String>,
+    reason_end: Option<String>,
+    skipped: Option<bool>,
+}
+
+impl StreamingRecord {
+    // Podcast and video entries have no track uri and aren't kept
+    fn into_play(self) -> Option<Play> {
+        let track_id = self
+            .spotify_track_uri?
+            .strip_prefix("spotify:track:")?
+            .to_string();
+        let skipped = self.skipped.unwrap_or(false)
+            || (self.reason_end.as_deref() == Some("fwdbtn") && self.ms_played < MIN_PLAY_MS);
+
+        Some(Play {
+            played_at: self.ts,
+            track_id,
+            ms_played: self.ms_played,
+            skipped,
+            track_name: self.master_metadata_track_name,
+            artist_name: self.master_metadata_album_artist_name,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PlayStats {
+    pub play_count: usize,
+    pub skip_count: usize,
+    pub ms_played: i64,
+    pub last_played: Option<DateTime<Utc>>,
+}
+
+impl PlayStats {
+    pub fn skip_ratio(&self) -> f64 {
+        let listens = self.play_count + self.skip_count;
+        if listens == 0 {
+            0.0
+        } else {
+            self.skip_count as f64 / listens as f64
+        }
+    }
+}
+
+pub fn compile_stats<'a>(plays: impl Iterator<Item = &'a Play>) -> HashMap<String, PlayStats> {
+    let mut stats: HashMap<String, PlayStats> = HashMap::new();
+    for play in plays {
+        let track_stats = stats.entry(play.track_id.clone()).or_default();
+        if play.skipped {
+            track_stats.skip_count += 1;
+        } else if play.ms_played >= MIN_PLAY_MS {
+            track_stats.play_count += 1;
+        }
+        track_stats.ms_played += play.ms_played;
+        if track_stats
+            .last_played
+            .map_or(true, |last| last < play.played_at)
+        {
+            track_stats.last_played = Some(play.played_at);
+        }
+    }
+    stats
+}
+
+// Restricts generated playlists and searches by listening history
+#[derive(Clone, Debug, Default)]
+pub struct HistoryFilter {
+    pub min_plays: Option<usize>,
+    pub max_skip_ratio: Option<f64>,
+    pub played_since: Option<DateTime<Utc>>,
+    pub not_played_since: Option<DateTime<Utc>>,
+}
+
+impl HistoryFilter {
+    pub fn is_empty(&self) -> bool {
+        self.min_plays.is_none()
+            && self.max_skip_ratio.is_none()
+            && self.played_since.is_none()
+            && self.not_played_since.is_none()
+    }
+
+    pub fn allows(&self, stats: Option<&PlayStats>) -> bool {
+        let default = PlayStats::default();
+        let stats = stats.unwrap_or(&default);
+        self.min_plays.map_or(true, |min| stats.play_count >= min)
+            && self
+                .max_skip_ratio
+                .map_or(true, |max| stats.skip_ratio() <= max)
+            && self
+                .played_since
+                .map_or(true, |since| stats.last_played >= Some(since))
+            && self.not_played_since.map_or(true, |since| {
+                stats.last_played.map_or(true, |last| last < since)
+            })
+    }
+}
+
+fn read_streaming_history(file: &str) -> Result<Vec<StreamingRecord>, String> {
+    let contents = fs::read_to_string(file).map_err(|err| err.to_string())?;
+    serde_json::from_str(&contents).map_err(|err| err.to_string())
+}
+
+// Imports the files from Spotify's extended streaming history export. Plays are keyed by time and
+// track so importing the same files again doesn't add anything
+pub fn import_streaming_history(library: &LibraryDatabase, files: &[String]) {
+    let mut plays = Vec::new();
+    for file in files {
+        let records = match read_streaming_history(file) {
+            Ok(records) => records,
+            Err(err) => {
+                println!("Skipping {}: {}", file, err);
+                continue;
+            }
+        };
+        let num_records = records.len();
+        let mut file_plays = records
+            .into_iter()
+            .filter_map(StreamingRecord::into_play)
+            .collect::<Vec<_>>();
+        println!(
+            "{}: {} track plays out of {} entries",
+            file,
+            file_plays.len(),
+            num_records
+        );
+        plays.append(&mut file_plays);
+    }
+
+    let tracks = library.retrieve_tracks();
+    let linked = plays
+        .iter()
+        .filter(|play| tracks.contains_key(&play.track_id))
+        .count();
+    let added = library.update_history(plays);
+    println!(
+        "Added {} new plays, {} linked to library tracks",
+        added, linked
+    );
+}
+
+// Stores the plays since the last collection, returning how many were new
+pub async fn collect_recent_plays(
+    spotify: &AuthCodeSpotify,
+    library: &LibraryDatabase,
+) -> Result<usize, String> {
+    let after = library
+        .retrieve_cursor(RECENTLY_PLAYED_CURSOR)
+        .and_then(|cursor| cursor.parse::<i64>().ok())
+        .and_then(|millis| Utc.timestamp_millis_opt(millis).single());
+
+    let plays = recently_played(spotify, after)
+        .await
+        .map_err(|err| format!("Couldn't fetch recently played tracks: {}", err))?
+        .into_iter()
+        .filter_map(Play::from_history)
+        .collect::<Vec<_>>();
+    let Some(latest) = plays.iter().map(|play| play.played_at).max() else {
+        println!("No new plays");
+        return Ok(0);
+    };
+
+    let tracks = library.retrieve_tracks();
+    let linked = plays
+        .iter()
+        .filter(|play| tracks.contains_key(&play.track_id))
+        .count();
+    let added = library.update_history(plays);
+    library.update_cursor(
+        RECENTLY_PLAYED_CURSOR,
+        latest.timestamp_millis().to_string(),
+    );
+    println!(
+        "Collected {} new plays, {} linked to library tracks",
+        added, linked
+    );
+    Ok(added)
+}
+
+// Polls forever, the interval has to be short enough that fewer than 50 tracks get played in it.
+// A failed poll is retried at the next interval
+pub async fn collect_plays_periodically(
+    spotify: &AuthCodeSpotify,
+    library: &LibraryDatabase,
+    interval_secs: u64,
+) {
+    loop {
+        if let Err(err) = collect_recent_plays(spotify, library).await {
+            println!("{}", err);
+        }
+        tokio::time::sleep(Duration::from_secs(interval_secs)).await;
+    }
+}
+
+pub fn print_top_played(library: &LibraryDatabase, num_tracks: usize) {
+    let tracks = library.retrieve_tracks();
+    let mut stats = library.play_stats().into_iter().collect::<Vec<_>>();
+    stats.sort_by(|(_, a), (_, b)| b.play_count.cmp(&a.play_count));
+
+    for (track_id, track_stats) in stats.into_iter().take(num_tracks) {
+        let name = match tracks.get(&track_id) {
+            Some(track) => format!("{} - {}", track.artists.get(0).unwrap().name, track.name),
+            None => track_id,
+        };
+        println!(
+            "{:>5} plays {:>4.0}% skipped  {}",
+            track_stats.play_count,
+            track_stats.skip_ratio() * 100.0,
+            name
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(json: &str) -> Option<Play> {
+        serde_json::from_str::<StreamingRecord>(json)
+            .unwrap()
+            .into_play()
+    }
+
+    fn play(track_id: &str, ms_played: i64, skipped: bool) -> Play {
+        Play {
+            played_at: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+            track_id: track_id.to_string(),
+            ms_played,
+            skipped,
+            track_name: None,
+            artist_name: None,
+        }
+    }
+
+    #[test]
+    fn streaming_record_keeps_tracks_only() {
+        let play = record(
+            r#"{"ts": "2023-05-01T12:00:00Z", "ms_played": 200000,
+                "spotify_track_uri": "spotify:track:4uLU6hMCjMI75M1A2tKUQC",
+                "master_metadata_track_name": "Song",
+                "master_metadata_album_artist_name": "Artist",
+                "reason_end": "trackdone", "skipped": null}"#,
+        )
+        .unwrap();
+        assert_eq!(play.track_id, "4uLU6hMCjMI75M1A2tKUQC");
+        assert!(!play.skipped);
+
+        let episode = record(
+            r#"{"ts": "2023-05-01T12:00:00Z", "ms_played": 200000, "spotify_track_uri": null,
+                "master_metadata_track_name": null, "master_metadata_album_artist_name": null,
+                "reason_end": "trackdone", "skipped": null}"#,
+        );
+        assert!(episode.is_none());
+    }
+
+    #[test]
+    fn early_forward_counts_as_skip() {
+        let play = record(
+            r#"{"ts": "2023-05-01T12:00:00Z", "ms_played": 5000,
+                "spotify_track_uri": "spotify:track:4uLU6hMCjMI75M1A2tKUQC",
+                "master_metadata_track_name": null, "master_metadata_album_artist_name": null,
+                "reason_end": "fwdbtn", "skipped": null}"#,
+        )
+        .unwrap();
+        assert!(play.skipped);
+    }
+
+    // The export has whole seconds and the recently played endpoint has milliseconds, the same
+    // play from both has to end up under one key
+    #[test]
+    fn same_play_from_export_and_api_shares_key() {
+        let exported = record(
+            r#"{"ts": "2023-05-01T12:00:00Z", "ms_played": 200000,
+                "spotify_track_uri": "spotify:track:4uLU6hMCjMI75M1A2tKUQC",
+                "master_metadata_track_name": null, "master_metadata_album_artist_name": null,
+                "reason_end": "trackdone", "skipped": null}"#,
+        )
+        .unwrap();
+        let played_at = "2023-05-01T12:00:00.734Z".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(
+            exported.key(),
+            play_key("4uLU6hMCjMI75M1A2tKUQC", &played_at)
+        );
+        assert_ne!(
+            exported.key(),
+            play_key(
+                "4uLU6hMCjMI75M1A2tKUQC",
+                &(played_at + chrono::Duration::seconds(1))
+            )
+        );
+    }
+
+    #[test]
+    fn stats_split_plays_and_skips() {
+        let plays = vec![
+            play("a", 200000, false),
+            play("a", 10000, false),
+            play("a", 5000, true),
+            play("b", 180000, false),
+        ];
+        let stats = compile_stats(plays.iter());
+        assert_eq!(stats["a"].play_count, 1);
+        assert_eq!(stats["a"].skip_count, 1);
+        assert_eq!(stats["a"].ms_played, 215000);
+        assert_eq!(stats["a"].skip_ratio(), 0.5);
+        assert_eq!(stats["b"].play_count, 1);
+    }
+
+    #[test]
+    fn filter_without_stats_counts_as_unplayed() {
+        let filter = HistoryFilter {
+            min_plays: Some(1),
+            ..Default::default()
+        };
+        assert!(!filter.allows(None));
+        assert!(HistoryFilter::default().allows(None));
+    }
+
+    #[test]
+    fn filter_by_last_play() {
+        let stats = compile_stats([play("a", 200000, false)].iter());
+        let played_at = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let before = played_at - chrono::Duration::days(1);
+        let after = played_at + chrono::Duration::days(1);
+
+        let played_since = |since| HistoryFilter {
+            played_since: Some(since),
+            ..Default::default()
+        };
+        assert!(played_since(before).allows(stats.get("a")));
+        assert!(played_since(played_at).allows(stats.get("a")));
+        assert!(!played_since(after).allows(stats.get("a")));
+        assert!(!played_since(before).allows(None));
+
+        let not_played_since = |since| HistoryFilter {
+            not_played_since: Some(since),
+            ..Default::default()
+        };
+        assert!(!not_played_since(before).allows(stats.get("a")));
+        assert!(not_played_since(after).allows(stats.get("a")));
+        assert!(not_played_since(before).allows(None));
+    }
+}
@@ -0,0 +1,692 @@
+use clap::ValueEnum;
+use itertools::Itertools;
+use rspotify::{
+    model::{FullTrack, SearchResult, SearchType, TrackId},
+    prelude::*,
+    AuthCodeSpotify,
+};
+use std::{collections::HashSet, fs, path::Path};
+
+use super::{
+    conversion::track_ids_to_tracks, playlists::add_tracks_to_playlist, storage::LibraryDatabase,
+};
+
+// Scores at or above this are treated as the same track
+static MATCH_THRESHOLD: f64 = 0.85;
+// Spotify search already filters on artist and title, so its results can be trusted a bit more
+static SEARCH_THRESHOLD: f64 = 0.7;
+// Two candidates this close are too close to pick between
+static AMBIGUITY_MARGIN: f64 = 0.02;
+// Durations further apart than this are probably different recordings
+static DURATION_TOLERANCE_MS: i64 = 5000;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum ImportFormat {
+    Text,
+    Csv,
+    M3u,
+}
+
+impl ImportFormat {
+    pub fn from_path(path: &str) -> ImportFormat {
+        match Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase())
+            .as_deref()
+        {
+            Some("csv") => ImportFormat::Csv,
+            Some("m3u") | Some("m3u8") => ImportFormat::M3u,
+            _ => ImportFormat::Text,
+        }
+    }
+}
+
+// A single track read from an import file
+#[derive(Clone, Debug)]
+pub struct ImportEntry {
+    pub line: usize,
+    pub artist: String,
+    pub title: String,
+    pub album: Option<String>,
+    pub duration_ms: Option<i64>,
+    pub uri: Option<String>,
+}
+
+impl ImportEntry {
+    fn describe(&self) -> String {
+        if self.artist.is_empty() {
+            self.title.clone()
+        } else {
+            format!("{} - {}", self.artist, self.title)
+        }
+    }
+}
+
+pub enum Resolution {
+    Matched(FullTrack),
+    Ambiguous(Vec<FullTrack>),
+    Unmatched,
+}
+
+pub fn parse_entries(contents: &str, format: ImportFormat) -> Vec<ImportEntry> {
+    match format {
+        ImportFormat::Text => parse_text(contents),
+        ImportFormat::Csv => parse_csv(contents),
+        ImportFormat::M3u => parse_m3u(contents),
+    }
+}
+
+fn split_artist_title(line: &str) -> (String, String) {
+    match line.split_once(" - ") {
+        Some((artist, title)) => (artist.trim().to_string(), title.trim().to_string()),
+        None => (String::new(), line.trim().to_string()),
+    }
+}
+
+fn parse_text(contents: &str) -> Vec<ImportEntry> {
+    let mut entries = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (artist, title) = split_artist_title(line);
+        entries.push(ImportEntry {
+            line: index + 1,
+            artist,
+            title,
+            album: None,
+            duration_ms: None,
+            uri: spotify_uri(line),
+        });
+    }
+    entries
+}
+
+fn spotify_uri(text: &str) -> Option<String> {
+    if text.starts_with("spotify:track:") {
+        Some(text.to_string())
+    } else if text.starts_with("https://open.spotify.com/track/") {
+        let id = text
+            .trim_start_matches("https://open.spotify.com/track/")
+            .split('?')
+            .next()?;
+        Some(format!("spotify:track:{}", id))
+    } else {
+        None
+    }
+}
+
+// Splits csv into rows of fields, handling quoted fields with commas, quotes and newlines
+fn csv_rows(contents: &str) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = contents.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => row.push(std::mem::take(&mut field)),
+            '\r' if !in_quotes => {}
+            '\n' if !in_quotes => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            _ => field.push(c),
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    rows
+}
+
+fn find_column(headers: &[String], names: &[&str]) -> Option<usize> {
+    headers
+        .iter()
+        .position(|header| names.contains(&header.trim().to_lowercase().as_str()))
+}
+
+// Durations come as milliseconds, seconds or m:ss depending on the service
+fn parse_track_length(text: &str, in_ms: bool) -> Option<i64> {
+    let text = text.trim();
+    if text.contains(':') {
+        let mut seconds = 0;
+        for part in text.split(':') {
+            seconds = seconds * 60 + part.trim().parse::<i64>().ok()?;
+        }
+        return Some(seconds * 1000);
+    }
+
+    let value = text.parse::<f64>().ok()?;
+    if in_ms || value > 10000.0 {
+        Some(value as i64)
+    } else {
+        Some((value * 1000.0) as i64)
+    }
+}
+
+fn parse_csv(contents: &str) -> Vec<ImportEntry> {
+    let rows = csv_rows(contents.trim_start_matches('\u{feff}'));
+    let Some((headers, rows)) = rows.split_first() else {
+        return Vec::new();
+    };
+
+    let artist = find_column(
+        headers,
+        &[
+            "artist",
+            "artists",
+            "artist name",
+            "artist name(s)",
+            "artist_name",
+        ],
+    );
+    let title = find_column(
+        headers,
+        &["title", "track", "track name", "track_name", "name", "song"],
+    );
+    let album = find_column(headers, &["album", "album name", "album_name"]);
+    let duration = find_column(
+        headers,
+        &[
+            "duration",
+            "duration_ms",
+            "duration (ms)",
+            "track duration (ms)",
+            "length",
+        ],
+    );
+    let duration_in_ms = duration
+        .map(|index| headers[index].to_lowercase().contains("ms"))
+        .unwrap_or(false);
+    let uri = find_column(
+        headers,
+        &[
+            "uri",
+            "spotify uri",
+            "spotify_uri",
+            "track uri",
+            "track_uri",
+        ],
+    );
+
+    let Some(title) = title else {
+        println!("No title column found in the csv headers: {:?}", headers);
+        return Vec::new();
+    };
+
+    let field = |row: &Vec<String>, index: Option<usize>| {
+        index
+            .and_then(|index| row.get(index))
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+
+    let mut entries = Vec::new();
+    for (index, row) in rows.iter().enumerate() {
+        let Some(track_title) = field(row, Some(title)) else {
+            continue;
+        };
+
+        entries.push(ImportEntry {
+            // Header is line 1
+            line: index + 2,
+            artist: field(row, artist).unwrap_or_default(),
+            title: track_title,
+            album: field(row, album),
+            duration_ms: field(row, duration)
+                .and_then(|value| parse_track_length(&value, duration_in_ms)),
+            uri: field(row, uri).and_then(|value| spotify_uri(&value)),
+        });
+    }
+    entries
+}
+
+fn parse_m3u(contents: &str) -> Vec<ImportEntry> {
+    let mut entries = Vec::new();
+    let mut info: Option<(Option<i64>, String)> = None;
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            info = extinf.split_once(',').map(|(seconds, name)| {
+                let duration = seconds
+                    .trim()
+                    .parse::<i64>()
+                    .ok()
+                    .filter(|seconds| *seconds > 0)
+                    .map(|seconds| seconds * 1000);
+                (duration, name.trim().to_string())
+            });
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+
+        // Without an #EXTINF line the file name is the best guess at "Artist - Title"
+        let (duration_ms, name) = info.take().unwrap_or_else(|| {
+            let stem = Path::new(line)
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or(line);
+            (None, stem.to_string())
+        });
+        let (artist, title) = split_artist_title(&name);
+        entries.push(ImportEntry {
+            line: index + 1,
+            artist,
+            title,
+            album: None,
+            duration_ms,
+            uri: spotify_uri(line),
+        });
+    }
+    entries
+}
+
+// Lowercases and strips punctuation, featured artists and remaster/version suffixes
+pub fn normalize(text: &str) -> String {
+    let lower = text.to_lowercase();
+    let mut cut = lower.as_str();
+    for marker in [
+        " (feat",
+        " [feat",
+        " feat.",
+        " ft.",
+        " - remaster",
+        " - live",
+        " (remaster",
+    ] {
+        if let Some(index) = cut.find(marker) {
+            cut = &cut[..index];
+        }
+    }
+
+    cut.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .join(" ")
+}
+
+// Dice coefficient over character bigrams
+fn similarity(a: &str, b: &str) -> f64 {
+    if a == b {
+        return 1.0;
+    }
+    let bigrams = |text: &str| {
+        text.chars()
+            .collect_vec()
+            .windows(2)
+            .map(|pair| (pair[0], pair[1]))
+            .collect::<HashSet<_>>()
+    };
+    let (a, b) = (bigrams(a), bigrams(b));
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    2.0 * a.intersection(&b).count() as f64 / (a.len() + b.len()) as f64
+}
+
+fn score(entry: &ImportEntry, track: &FullTrack) -> f64 {
+    let title = similarity(&normalize(&entry.title), &normalize(&track.name));
+    let artist = if entry.artist.is_empty() {
+        1.0
+    } else {
+        let entry_artist = normalize(&entry.artist);
+        track
+            .artists
+            .iter()
+            .map(|artist| {
+                let name = normalize(&artist.name);
+                if entry_artist.contains(&name) {
+                    1.0
+                } else {
+                    similarity(&entry_artist, &name)
+                }
+            })
+            .fold(0.0, f64::max)
+    };
+
+    let mut score = 0.65 * title + 0.35 * artist;
+    if let Some(album) = &entry.album {
+        if normalize(album) == normalize(&track.album.name) {
+            score += 0.05;
+        }
+    }
+    if let Some(duration) = entry.duration_ms {
+        if (duration - track.duration.num_milliseconds()).abs() > DURATION_TOLERANCE_MS {
+            score -= 0.2;
+        }
+    }
+    score
+}
+
+fn same_song(a: &FullTrack, b: &FullTrack) -> bool {
+    normalize(&a.name) == normalize(&b.name)
+        && a.artists.first().map(|artist| normalize(&artist.name))
+            == b.artists.first().map(|artist| normalize(&artist.name))
+}
+
+// Picks the best candidate above the threshold, or reports the tie if different songs score the same
+fn best_candidate(entry: &ImportEntry, candidates: &[FullTrack], threshold: f64) -> Resolution {
+    let scored = candidates
+        .iter()
+        .map(|track| (score(entry, track), track))
+        .filter(|(score, _)| *score >= threshold)
+        .sorted_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap())
+        .collect_vec();
+
+    let Some((best_score, best)) = scored.first() else {
+        return Resolution::Unmatched;
+    };
+
+    let mut tied = scored
+        .iter()
+        .filter(|(score, track)| best_score - score <= AMBIGUITY_MARGIN && !same_song(best, track))
+        .map(|(_, track)| (*track).clone())
+        .collect_vec();
+    if tied.is_empty() {
+        Resolution::Matched((*best).clone())
+    } else {
+        tied.insert(0, (*best).clone());
+        Resolution::Ambiguous(tied)
+    }
+}
+
+fn resolve_in_library(entry: &ImportEntry, library_tracks: &[FullTrack]) -> Resolution {
+    let title = normalize(&entry.title);
+    let exact = library_tracks
+        .iter()
+        .filter(|track| normalize(&track.name) == title)
+        .cloned()
+        .collect_vec();
+    match best_candidate(entry, &exact, MATCH_THRESHOLD) {
+        Resolution::Unmatched => best_candidate(entry, library_tracks, MATCH_THRESHOLD),
+        resolution => resolution,
+    }
+}
+
+// Searches in the library's market so only tracks that can be played there are matched
+async fn resolve_with_search(
+    spotify: &AuthCodeSpotify,
+    library: &LibraryDatabase,
+    entry: &ImportEntry,
+) -> Resolution {
+    let query = if entry.artist.is_empty() {
+        format!("track:{}", entry.title)
+    } else {
+        format!("track:{} artist:{}", entry.title, entry.artist)
+    };
+
+    match spotify
+        .search(
+            &query,
+            SearchType::Track,
+            Some(library.market()),
+            None,
+            Some(5),
+            None,
+        )
+        .await
+    {
+        Ok(SearchResult::Tracks(page)) => {
+            let playable = page
+                .items
+                .into_iter()
+                .filter(|track| track.is_playable != Some(false))
+                .collect_vec();
+            best_candidate(entry, &playable, SEARCH_THRESHOLD)
+        }
+        _ => Resolution::Unmatched,
+    }
+}
+
+pub async fn resolve_entries(
+    spotify: &AuthCodeSpotify,
+    library: &LibraryDatabase,
+    entries: &[ImportEntry],
+) -> Vec<(ImportEntry, Resolution)> {
+    let library_tracks = library.retrieve_tracks().into_values().collect_vec();
+
+    let uri_ids = entries
+        .iter()
+        .filter_map(|entry| entry.uri.as_ref())
+        .filter_map(|uri| TrackId::from_uri(uri).ok())
+        .map(|id| id.into_static())
+        .unique()
+        .collect_vec();
+    let uri_tracks = track_ids_to_tracks(spotify, uri_ids, None).await;
+
+    let mut resolved = Vec::new();
+    for entry in entries {
+        let by_uri = entry.uri.as_ref().and_then(|uri| {
+            uri_tracks
+                .iter()
+                .find(|track| track.id.as_ref().map(|id| id.uri()).as_ref() == Some(uri))
+        });
+
+        let resolution = match by_uri {
+            Some(track) => Resolution::Matched(track.clone()),
+            None => match resolve_in_library(entry, &library_tracks) {
+                Resolution::Unmatched => resolve_with_search(spotify, library, entry).await,
+                resolution => resolution,
+            },
+        };
+        resolved.push((entry.clone(), resolution));
+    }
+    resolved
+}
+
+// Without a playlist it's a dry run, the matches are only reported
+pub async fn import_tracks(
+    spotify: &AuthCodeSpotify,
+    library: &LibraryDatabase,
+    playlist_id: Option<&str>,
+    path: &str,
+    format: Option<ImportFormat>,
+) -> Result<(), String> {
+    let contents =
+        fs::read_to_string(path).map_err(|err| format!("Couldn't read {}: {}", path, err))?;
+    let format = format.unwrap_or_else(|| ImportFormat::from_path(path));
+    let entries = parse_entries(&contents, format);
+    let resolved = resolve_entries(spotify, library, &entries).await;
+
+    let mut matched = Vec::new();
+    let mut unmatched = Vec::new();
+    let mut ambiguous = Vec::new();
+    for (entry, resolution) in resolved {
+        match resolution {
+            Resolution::Matched(track) => matched.push(track),
+            Resolution::Ambiguous(candidates) => ambiguous.push((entry, candidates)),
+            Resolution::Unmatched => unmatched.push(entry),
+        }
+    }
+
+    println!(
+        "Matched {} of {} entries, {} ambiguous, {} unmatched",
+        matched.len(),
+        entries.len(),
+        ambiguous.len(),
+        unmatched.len()
+    );
+    for entry in &unmatched {
+        println!("Unmatched line {}: {}", entry.line, entry.describe());
+    }
+    for (entry, candidates) in &ambiguous {
+        println!("Ambiguous line {}: {}", entry.line, entry.describe());
+        for track in candidates {
+            println!(
+                "    {} - {} ({})",
+                track.artists.iter().map(|artist| &artist.name).join(", "),
+                track.name,
+                track.album.name
+            );
+        }
+    }
+
+    if let Some(playlist_id) = playlist_id {
+        add_tracks_to_playlist(spotify, playlist_id, matched, None).await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::fixtures::track;
+
+    fn entry(artist: &str, title: &str, duration_ms: Option<i64>) -> ImportEntry {
+        ImportEntry {
+            line: 1,
+            artist: artist.to_string(),
+            title: title.to_string(),
+            album: None,
+            duration_ms,
+            uri: None,
+        }
+    }
+
+    fn matched_id(resolution: Resolution) -> Option<String> {
+        match resolution {
+            Resolution::Matched(track) => Some(track.id.unwrap().id().to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn text_lines_split_on_the_dash() {
+        let entries = parse_text(
+            "# comment\nArtist - Song - Live\n\nJust A Title\nhttps://open.spotify.com/track/abc?si=x\n",
+        );
+        assert_eq!(entries.len(), 3);
+        assert_eq!((entries[0].line, entries[0].artist.as_str()), (2, "Artist"));
+        assert_eq!(entries[0].title, "Song - Live");
+        assert_eq!(
+            (entries[1].artist.as_str(), entries[1].title.as_str()),
+            ("", "Just A Title")
+        );
+        assert_eq!(entries[2].uri.as_deref(), Some("spotify:track:abc"));
+    }
+
+    #[test]
+    fn csv_reads_quoted_fields_and_known_headers() {
+        let entries = parse_csv(
+            "\u{feff}Track Name,Artist Name(s),Album Name,Track Duration (ms)\r\n\
+             \"Song, Part 1\",\"The \"\"Band\"\"\",Album,200000\r\n\
+             ,Nobody,Album,1000\r\n\
+             \"Two\nLines\",Artist,,3:20\r\n",
+        );
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].title, "Song, Part 1");
+        assert_eq!(entries[0].artist, "The \"Band\"");
+        assert_eq!(entries[0].album.as_deref(), Some("Album"));
+        assert_eq!(entries[0].duration_ms, Some(200000));
+        assert_eq!(entries[1].title, "Two\nLines");
+        assert_eq!(entries[1].album, None);
+        assert_eq!(entries[1].duration_ms, Some(200000));
+        assert_eq!(entries[1].line, 4);
+    }
+
+    #[test]
+    fn csv_without_a_title_column_is_empty() {
+        assert!(parse_csv("artist,album\nA,B\n").is_empty());
+    }
+
+    #[test]
+    fn track_lengths_in_every_unit() {
+        assert_eq!(parse_track_length("200000", true), Some(200000));
+        assert_eq!(parse_track_length("200", false), Some(200000));
+        assert_eq!(parse_track_length("200000", false), Some(200000));
+        assert_eq!(parse_track_length("3:20", false), Some(200000));
+        assert_eq!(parse_track_length("1:03:20", false), Some(3800000));
+        assert_eq!(parse_track_length("long", false), None);
+    }
+
+    #[test]
+    fn m3u_uses_extinf_or_the_file_name() {
+        let entries = parse_m3u(
+            "#EXTM3U\n#EXTINF:200,Artist - Song\nmusic/song.mp3\n#EXTINF:-1,Stream\nhttp://radio\nmusic/Other - Tune.flac\n",
+        );
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].artist, "Artist");
+        assert_eq!(entries[0].duration_ms, Some(200000));
+        assert_eq!(entries[1].duration_ms, None);
+        assert_eq!(entries[1].title, "Stream");
+        assert_eq!(
+            (entries[2].artist.as_str(), entries[2].title.as_str()),
+            ("Other", "Tune")
+        );
+    }
+
+    #[test]
+    fn normalize_strips_features_and_versions() {
+        assert_eq!(normalize("Song (feat. Someone)"), "song");
+        assert_eq!(normalize("Song - Remastered 2011"), "song");
+        assert_eq!(normalize("Don't   Stop!"), "don t stop");
+        assert_eq!(normalize("Song - Live at Wembley"), "song");
+    }
+
+    #[test]
+    fn similarity_is_dice_over_bigrams() {
+        assert_eq!(similarity("night", "night"), 1.0);
+        // ni ig gh ht against na ac ch ht share only ht
+        assert_eq!(similarity("night", "nacht"), 0.25);
+        assert_eq!(similarity("a", "b"), 0.0);
+        assert_eq!(similarity("", "song"), 0.0);
+    }
+
+    #[test]
+    fn close_spellings_pass_the_threshold() {
+        let tracks = [
+            track("right", "Colour Me Blue", "The Band", "Album", 200000),
+            track("wrong", "Something Else", "The Band", "Album", 200000),
+        ];
+        let close = entry("The Band", "Color Me Blue", None);
+        assert!(score(&close, &tracks[0]) >= MATCH_THRESHOLD);
+        assert_eq!(
+            matched_id(best_candidate(&close, &tracks, MATCH_THRESHOLD)).as_deref(),
+            Some("right")
+        );
+
+        let far = entry("The Band", "Blue Monday", None);
+        assert!(matched_id(best_candidate(&far, &tracks, MATCH_THRESHOLD)).is_none());
+    }
+
+    #[test]
+    fn a_different_length_drops_below_the_threshold() {
+        let tracks = [track("song", "Song", "Artist", "Album", 200000)];
+        let same = entry("Artist", "Song", Some(202000));
+        let other = entry("Artist", "Song", Some(260000));
+        assert!(matched_id(best_candidate(&same, &tracks, MATCH_THRESHOLD)).is_some());
+        assert!(matched_id(best_candidate(&other, &tracks, MATCH_THRESHOLD)).is_none());
+    }
+
+    #[test]
+    fn different_songs_scoring_the_same_are_ambiguous() {
+        let tracks = [
+            track("one", "Song", "Artist", "First", 200000),
+            track("two", "Song", "Other Artist", "Second", 200000),
+        ];
+        let untitled = entry("", "Song", None);
+        assert!(matches!(
+            best_candidate(&untitled, &tracks, MATCH_THRESHOLD),
+            Resolution::Ambiguous(candidates) if candidates.len() == 2
+        ));
+    }
+}
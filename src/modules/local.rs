@@ -0,0 +1,29 @@
+use rspotify::{model::FullTrack, prelude::*};
+
+use super::playlists::playlist_track_id;
+
+// Local files have no Spotify id, and the uri Spotify has for them isn't in the api's track
+// objects, so they're told apart by a key made from the file's tags. It's only used inside rspot,
+// never as a uri
+pub fn local_key(track: &FullTrack) -> String {
+    let artist = track
+        .artists
+        .get(0)
+        .map(|artist| artist.name.as_str())
+        .unwrap_or_default();
+    format!(
+        "local:{}|{}|{}|{}",
+        artist,
+        track.album.name,
+        track.name,
+        track.duration.num_seconds()
+    )
+}
+
+// The saved id of a Spotify track or the key of a local file
+pub fn track_key(track: &FullTrack) -> String {
+    match playlist_track_id(track) {
+        Some(id) if !track.is_local => id.id().to_string(),
+        _ => local_key(track),
+    }
+}
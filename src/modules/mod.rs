@@ -1,5 +1,26 @@
+pub mod availability;
+pub mod clean;
 pub mod conversion;
+pub mod decades;
+pub mod dedupe;
+pub mod discography;
+pub mod duration;
+pub mod export;
+pub mod features;
+#[cfg(test)]
+pub mod fixtures;
+pub mod genres;
+pub mod history;
+pub mod import;
+pub mod local;
+pub mod ordering;
 pub mod playlists;
+pub mod releases;
+pub mod removal;
+pub mod retention;
 pub mod retrieve;
+pub mod sampling;
+pub mod settings;
+pub mod snapshots;
 pub mod storage;
 pub mod token;
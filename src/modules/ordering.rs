@@ -0,0 +1,291 @@
+use clap::ValueEnum;
+use rspotify::{model::FullTrack, prelude::*};
+use std::collections::HashMap;
+
+use super::{features::TrackFeatures, storage::LibraryDatabase};
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum TrackOrder {
+    /// Leaves the tracks in the order the generator picked them
+    Keep,
+    /// Spreads out tracks by the same artist or from the same album
+    Spaced,
+    /// Mixes from each track into one in a compatible key and a similar tempo
+    Harmonic,
+}
+
+pub struct OrderOptions {
+    pub order: TrackOrder,
+    pub min_gap: usize,
+    pub max_bpm_change: f64,
+    features: HashMap<String, TrackFeatures>,
+}
+
+impl OrderOptions {
+    // Audio features are only loaded when the order needs them
+    pub fn new(
+        library: &LibraryDatabase,
+        order: TrackOrder,
+        min_gap: usize,
+        max_bpm_change: f64,
+    ) -> OrderOptions {
+        OrderOptions {
+            order,
+            min_gap,
+            max_bpm_change,
+            features: match order {
+                TrackOrder::Harmonic => library.retrieve_features(),
+                _ => HashMap::new(),
+            },
+        }
+    }
+}
+
+pub fn order_tracks(tracks: Vec<FullTrack>, options: &OrderOptions) -> Vec<FullTrack> {
+    match options.order {
+        TrackOrder::Keep => tracks,
+        TrackOrder::Spaced => space_tracks(tracks, options.min_gap),
+        TrackOrder::Harmonic => harmonic_tracks(tracks, &options.features, options.max_bpm_change),
+    }
+}
+
+// Spaced by id so different artists or albums sharing a name aren't kept apart. Local files have
+// no ids so their names are used instead
+fn spacing_keys(track: &FullTrack) -> Vec<String> {
+    let mut keys = track
+        .artists
+        .iter()
+        .map(|artist| match &artist.id {
+            Some(id) => format!("artist:{}", id.id()),
+            None => format!("artist_name:{}", artist.name),
+        })
+        .collect::<Vec<_>>();
+    keys.push(match &track.album.id {
+        Some(id) => format!("album:{}", id.id()),
+        None => format!("album_name:{}", track.album.name),
+    });
+    keys
+}
+
+fn primary_artist(track: &FullTrack) -> String {
+    match track.artists.get(0) {
+        Some(artist) => match &artist.id {
+            Some(id) => id.id().to_string(),
+            None => artist.name.clone(),
+        },
+        None => String::new(),
+    }
+}
+
+// Greedily builds the order one track at a time. Tracks at least min_gap away from the last track
+// sharing an artist or album are preferred, and among those the artist with the most tracks left
+// goes first so the big artists don't all bunch up at the end. When nothing fits the gap the track
+// furthest from its last neighbour is used
+pub fn space_tracks(tracks: Vec<FullTrack>, min_gap: usize) -> Vec<FullTrack> {
+    let mut remaining = tracks;
+    let mut artist_counts: HashMap<String, usize> = HashMap::new();
+    for track in &remaining {
+        *artist_counts.entry(primary_artist(track)).or_insert(0) += 1;
+    }
+
+    let mut last_position: HashMap<String, usize> = HashMap::new();
+    let mut spaced = Vec::with_capacity(remaining.len());
+    while !remaining.is_empty() {
+        let position = spaced.len();
+        let gap = |track: &FullTrack| {
+            spacing_keys(track)
+                .iter()
+                .filter_map(|key| last_position.get(key))
+                .map(|last| position - last)
+                .min()
+                .unwrap_or(usize::MAX)
+        };
+
+        let best = remaining
+            .iter()
+            .enumerate()
+            .max_by_key(|(index, track)| {
+                let gap = gap(*track);
+                let fits = gap >= min_gap;
+                let count = if fits {
+                    artist_counts[&primary_artist(track)]
+                } else {
+                    0
+                };
+                // Earlier tracks win ties so the generator's order is kept where possible
+                (fits, count, gap, std::cmp::Reverse(*index))
+            })
+            .map(|(index, _)| index)
+            .unwrap();
+
+        let track = remaining.remove(best);
+        for key in spacing_keys(&track) {
+            last_position.insert(key, position);
+        }
+        *artist_counts.get_mut(&primary_artist(&track)).unwrap() -= 1;
+        spaced.push(track);
+    }
+    spaced
+}
+
+// Position on the Camelot wheel, the number from 1 to 12 and whether it's the major (B) side.
+// Moving a fifth up is one step clockwise and a minor key shares its number with its relative
+// major, three semitones up
+fn camelot(features: &TrackFeatures) -> Option<(i32, bool)> {
+    if !(0..12).contains(&features.key) {
+        return None;
+    }
+    let major = features.mode == 1;
+    let offset = if major { 8 } else { 5 };
+    let number = (7 * features.key + offset) % 12;
+    Some((if number == 0 { 12 } else { number }, major))
+}
+
+// Same key, the relative key, or one step either way round the wheel
+fn keys_compatible(a: &TrackFeatures, b: &TrackFeatures) -> bool {
+    match (camelot(a), camelot(b)) {
+        (Some((a_number, a_major)), Some((b_number, b_major))) => {
+            let steps = (a_number - b_number).rem_euclid(12);
+            a_number == b_number || (a_major == b_major && (steps == 1 || steps == 11))
+        }
+        _ => false,
+    }
+}
+
+// Half and double time mix as well as the same tempo
+fn tempo_change(a: &TrackFeatures, b: &TrackFeatures) -> f64 {
+    let (a, b) = (a.tempo as f64, b.tempo as f64);
+    [b, b * 2.0, b / 2.0]
+        .iter()
+        .map(|tempo| (a - tempo).abs())
+        .fold(f64::MAX, f64::min)
+}
+
+// Starts from the generator's first track and each time picks the next track in a compatible key
+// within the BPM change. When nothing fits it falls back to a compatible key at any tempo, then
+// any key within the tempo change, then the closest tempo. Tracks without features go at the end
+pub fn harmonic_tracks(
+    tracks: Vec<FullTrack>,
+    features: &HashMap<String, TrackFeatures>,
+    max_bpm_change: f64,
+) -> Vec<FullTrack> {
+    let track_features = |track: &FullTrack| {
+        track
+            .id
+            .as_ref()
+            .and_then(|id| features.get(id.id()))
+            .cloned()
+    };
+    let (mut remaining, missing): (Vec<_>, Vec<_>) = tracks
+        .into_iter()
+        .partition(|track| track_features(track).is_some());
+    if remaining.is_empty() {
+        return missing;
+    }
+
+    let mut ordered = vec![remaining.remove(0)];
+    let mut clashes = 0;
+    while !remaining.is_empty() {
+        let current = track_features(ordered.last().unwrap()).unwrap();
+        let (best, tier) = remaining
+            .iter()
+            .enumerate()
+            .map(|(index, track)| {
+                let next = track_features(track).unwrap();
+                let change = tempo_change(&current, &next);
+                let tier = match (keys_compatible(&current, &next), change <= max_bpm_change) {
+                    (true, true) => 0,
+                    (true, false) => 1,
+                    (false, true) => 2,
+                    (false, false) => 3,
+                };
+                (index, tier, change)
+            })
+            // Earlier tracks win ties so the generator's order is kept where possible
+            .min_by(|(a_index, a_tier, a_change), (b_index, b_tier, b_change)| {
+                (a_tier, a_change, a_index)
+                    .partial_cmp(&(b_tier, b_change, b_index))
+                    .unwrap()
+            })
+            .map(|(index, tier, _)| (index, tier))
+            .unwrap();
+
+        if tier > 0 {
+            clashes += 1;
+        }
+        ordered.push(remaining.remove(best));
+    }
+
+    if clashes > 0 {
+        println!(
+            "{} transitions aren't harmonic or go past the BPM change",
+            clashes
+        );
+    }
+    if !missing.is_empty() {
+        println!(
+            "{} tracks without audio features go at the end",
+            missing.len()
+        );
+    }
+    ordered.extend(missing);
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn features(key: i32, mode: i32, tempo: f32) -> TrackFeatures {
+        TrackFeatures {
+            tempo,
+            energy: 0.5,
+            danceability: 0.5,
+            valence: 0.5,
+            key,
+            mode,
+            acousticness: 0.1,
+        }
+    }
+
+    #[test]
+    fn keys_land_on_the_wheel() {
+        // C major, A minor, G major, E major and C# minor
+        assert_eq!(camelot(&features(0, 1, 120.0)), Some((8, true)));
+        assert_eq!(camelot(&features(9, 0, 120.0)), Some((8, false)));
+        assert_eq!(camelot(&features(7, 1, 120.0)), Some((9, true)));
+        assert_eq!(camelot(&features(4, 1, 120.0)), Some((12, true)));
+        assert_eq!(camelot(&features(1, 0, 120.0)), Some((12, false)));
+        assert_eq!(camelot(&features(-1, 1, 120.0)), None);
+    }
+
+    #[test]
+    fn neighbours_are_compatible() {
+        let c_major = features(0, 1, 120.0);
+        // The relative minor and a fifth either way
+        assert!(keys_compatible(&c_major, &features(9, 0, 120.0)));
+        assert!(keys_compatible(&c_major, &features(7, 1, 120.0)));
+        assert!(keys_compatible(&c_major, &features(5, 1, 120.0)));
+        // Two steps round and the neighbour's relative minor
+        assert!(!keys_compatible(&c_major, &features(2, 1, 120.0)));
+        assert!(!keys_compatible(&c_major, &features(4, 0, 120.0)));
+        assert!(!keys_compatible(&c_major, &features(-1, 1, 120.0)));
+    }
+
+    #[test]
+    fn wheel_wraps_from_12_to_1() {
+        // E major is 12B and B major is 1B
+        assert!(keys_compatible(
+            &features(4, 1, 120.0),
+            &features(11, 1, 120.0)
+        ));
+    }
+
+    #[test]
+    fn half_and_double_time_match() {
+        let a = features(0, 1, 120.0);
+        assert_eq!(tempo_change(&a, &features(0, 1, 60.0)), 0.0);
+        assert_eq!(tempo_change(&a, &features(0, 1, 240.0)), 0.0);
+        assert_eq!(tempo_change(&a, &features(0, 1, 126.0)), 6.0);
+    }
+}
@@ -1,29 +1,195 @@
+use chrono::{Duration, Utc};
 use futures::stream::TryStreamExt;
 use futures_util::pin_mut;
+use rand::rngs::StdRng;
 
 use itertools::Itertools;
+use reqwest::StatusCode;
 use rspotify::{
-    model::{FullTrack, PlayableItem, PlaylistId},
+    http::HttpError,
+    model::{FullTrack, Market, PlayableId, PlayableItem, PlaylistId, TrackId},
     prelude::*,
-    AuthCodeSpotify,
+    AuthCodeSpotify, ClientError, ClientResult,
 };
+use std::collections::{HashMap, HashSet};
 
 use super::{
-    conversion::{id_to_playable_ids, saved_tracks_to_tracks, track_ids_to_tracks},
-    retrieve::recently_added_tracks,
+    conversion::{saved_tracks_to_tracks, unescape_html},
+    dedupe::{canonical_key, dedupe_tracks, ReleasePreference},
+    duration::{total_duration, DurationTarget, TrackLimit},
+    features::FeatureQuery,
+    history::HistoryFilter,
+    local::local_key,
+    ordering::{order_tracks, OrderOptions},
+    removal::remove_entries,
+    retention::{AddedDates, RetentionPolicy},
+    retrieve::{episodes, recently_added_tracks},
+    sampling::{
+        group_album_tracks, sample_albums, sample_tracks, sample_without_repeats, sampled_ids,
+        seeded_rng, AlbumSampling, Cooldown, SampleContext, SampleOptions,
+    },
+    settings::Settings,
+    snapshots::SnapshotStore,
     storage::LibraryDatabase,
 };
-use rand::{seq::IteratorRandom, thread_rng};
+
+// Details used when rspot has to create a playlist for the current user
+pub struct PlaylistDetails {
+    pub name: String,
+    pub description: Option<String>,
+    pub public: bool,
+    pub collaborative: bool,
+}
+
+impl PlaylistDetails {
+    // What the generators create their playlists with
+    pub fn private(name: String, description: String) -> PlaylistDetails {
+        PlaylistDetails {
+            name,
+            description: Some(description),
+            public: false,
+            collaborative: false,
+        }
+    }
+}
+
+// Creates a playlist and records its id in the settings under the given key
+pub async fn create_playlist(
+    spotify: &AuthCodeSpotify,
+    settings: &mut Settings,
+    key: &str,
+    details: &PlaylistDetails,
+) -> Result<String, String> {
+    let user = spotify
+        .current_user()
+        .await
+        .map_err(|err| format!("Couldn't look up the current user: {}", err))?;
+    let playlist = spotify
+        .user_playlist_create(
+            user.id,
+            &details.name,
+            Some(details.public),
+            Some(details.collaborative),
+            details.description.as_deref(),
+        )
+        .await
+        .map_err(|err| format!("Couldn't create playlist {}: {}", details.name, err))?;
+
+    let playlist_id = playlist.id.id().to_string();
+    println!("Created playlist {} ({})", details.name, playlist_id);
+    settings.record_playlist(key, &playlist_id);
+    Ok(playlist_id)
+}
+
+// Turns a playlist argument into a playlist id. The target is either a key recorded in the
+// settings or a playlist id, and when neither exists the playlist is created if details are given
+pub async fn resolve_playlist(
+    spotify: &AuthCodeSpotify,
+    settings: &mut Settings,
+    target: &str,
+    details: Option<&PlaylistDetails>,
+) -> Result<String, String> {
+    if let Some(playlist_id) = settings.playlist_id(target) {
+        return Ok(playlist_id.clone());
+    }
+
+    let exists = playlist_exists(spotify, target)
+        .await
+        .map_err(|err| format!("Couldn't look up playlist {}: {}", target, err))?;
+    if exists {
+        return Ok(target.to_string());
+    }
+
+    match details {
+        Some(details) => create_playlist(spotify, settings, target, details).await,
+        None => Err(format!(
+            "Playlist {} doesn't exist, pass --create to create it",
+            target
+        )),
+    }
+}
+
+// Resolves the playlist argument and snapshots the playlist before it gets modified
+pub async fn prepare_playlist(
+    spotify: &AuthCodeSpotify,
+    settings: &mut Settings,
+    snapshots: &SnapshotStore,
+    target: &str,
+    details: Option<&PlaylistDetails>,
+) -> Result<String, String> {
+    let playlist_id = resolve_playlist(spotify, settings, target, details).await?;
+    snapshots.snapshot(spotify, &playlist_id).await;
+    Ok(playlist_id)
+}
+
+// Which buckets of a split library get a playlist. A single bucket is synced regardless of its size
+pub struct SplitOptions {
+    pub prefix: &'static str,
+    pub only: Option<String>,
+    pub min_songs: usize,
+}
+
+// Syncs a playlist for each bucket of a split library, creating them as needed under
+// "<prefix>:<bucket>"
+pub async fn sync_split_playlists(
+    spotify: &AuthCodeSpotify,
+    settings: &mut Settings,
+    snapshots: &SnapshotStore,
+    buckets: HashMap<String, Vec<FullTrack>>,
+    split: &SplitOptions,
+    order: &OrderOptions,
+) -> Result<(), String> {
+    for (bucket, tracks) in buckets.into_iter().sorted_by(|(a, _), (b, _)| a.cmp(b)) {
+        match split.only.as_deref() {
+            Some(only) if !only.eq_ignore_ascii_case(&bucket) => continue,
+            None if tracks.len() < split.min_songs => {
+                println!("Skipping {}, only {} songs", bucket, tracks.len());
+                continue;
+            }
+            _ => {}
+        }
+
+        println!("Syncing {} with {} songs", bucket, tracks.len());
+        let details =
+            PlaylistDetails::private(bucket.clone(), format!("{} songs from the library", bucket));
+        let key = format!("{}:{}", split.prefix, bucket.to_lowercase());
+        let playlist = prepare_playlist(spotify, settings, snapshots, &key, Some(&details)).await?;
+        clear_playlist(spotify, &playlist).await;
+        add_tracks_to_playlist(spotify, &playlist, order_tracks(tracks, order), None).await;
+    }
+    Ok(())
+}
+
+// Only a 404 means the playlist isn't there. Any other error is passed up, otherwise a failed
+// request would end with a second copy of the playlist being created
+async fn playlist_exists(spotify: &AuthCodeSpotify, playlist_id: &str) -> ClientResult<bool> {
+    let Ok(playlist_id) = PlaylistId::from_id(playlist_id) else {
+        return Ok(false);
+    };
+    match spotify.playlist(playlist_id, None, None).await {
+        Ok(_) => Ok(true),
+        Err(ClientError::Http(err)) if is_not_found(&err) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+fn is_not_found(err: &HttpError) -> bool {
+    match err {
+        HttpError::StatusCode(response) => response.status() == StatusCode::NOT_FOUND,
+        _ => false,
+    }
+}
 
 pub async fn update_recently_added(
     spotify: &AuthCodeSpotify,
     library: &LibraryDatabase,
     playlist_id: &str,
-    num_songs: usize,
+    policy: &RetentionPolicy,
 ) {
     println!("Updating recently added");
-    let recent_tracks =
-        saved_tracks_to_tracks(recently_added_tracks(spotify, library, Some(num_songs)).await);
+    let recent_tracks = saved_tracks_to_tracks(
+        recently_added_tracks(spotify, library, Some(songs_to_fetch(&policy.limit))).await,
+    );
     let playlist_tracks = get_playlist_tracks(spotify, &PlaylistId::from_id(playlist_id).unwrap())
         .await
         .into_iter()
@@ -50,7 +216,17 @@ pub async fn update_recently_added(
 
     add_tracks_to_playlist(spotify, playlist_id, new_tracks_to_add, Some(0)).await;
     add_tracks_to_playlist(spotify, playlist_id, old_tracks_to_add, None).await;
-    remove_old_tracks_from_playlist(spotify, playlist_id, num_songs).await;
+    remove_old_tracks_from_playlist(spotify, library, playlist_id, policy).await;
+}
+
+// Enough recent tracks to fill the duration, assuming they're at least two minutes long
+fn songs_to_fetch(limit: &TrackLimit) -> usize {
+    match limit {
+        TrackLimit::Duration(target) => {
+            ((target.target + target.tolerance).num_minutes() / 2) as usize
+        }
+        TrackLimit::Count(num_songs) => *num_songs,
+    }
 }
 
 pub async fn add_new_tracks_to_playlist(
@@ -86,55 +262,23 @@ pub async fn add_new_tracks_to_playlist(
     add_tracks_to_playlist(spotify, playlist_id, old_tracks_to_add, None).await;
 }
 
+// Trims the playlist down to what the policy keeps. Removals go by position so an earlier copy of
+// a trimmed track stays
 pub async fn remove_old_tracks_from_playlist(
     spotify: &AuthCodeSpotify,
+    library: &LibraryDatabase,
     playlist_id: &str,
-    num_songs: usize,
+    policy: &RetentionPolicy,
 ) {
     let playlist_id = PlaylistId::from_id(playlist_id).unwrap();
-    let playlist_tracks = get_playlist_tracks(spotify, &playlist_id).await;
-
-    if playlist_tracks.len() > num_songs {
-        // This is such jank ass code
-        let mut to_remove_tracks = playlist_tracks[num_songs - 1..].into_iter();
-        let mut index_of_last_valid_song = num_songs - 1;
-
-        let mut prev_track = playlist_tracks.get(index_of_last_valid_song).unwrap();
-        while let Some(curr_track) = to_remove_tracks.next() {
-            if prev_track.album.id != curr_track.album.id {
-                break;
-            }
-            index_of_last_valid_song += 1;
-            prev_track = curr_track;
-        }
-
-        let to_remove_tracks = &playlist_tracks[index_of_last_valid_song..];
-        let to_remove_id = to_remove_tracks
-            .iter()
-            .map(|track| track.clone().id.unwrap())
-            .collect::<Vec<_>>();
-        let to_remove_playable_id = id_to_playable_ids(&to_remove_id);
-        let to_remove_tracks = track_ids_to_tracks(&spotify, to_remove_id).await;
-        for track in to_remove_tracks {
-            println!(
-                "Removing {} - {}",
-                track.artists.get(0).unwrap().name,
-                track.name
-            )
-        }
-
-        let to_remove_chunks = paginate_vec(to_remove_playable_id, 99);
-
-        for chunk in to_remove_chunks {
-            spotify
-                .playlist_remove_all_occurrences_of_items(
-                    playlist_id.clone(),
-                    chunk.into_iter(),
-                    None,
-                )
-                .await
-                .unwrap();
-        }
+    let dates = AddedDates::new(library);
+    let now = Utc::now();
+    let removed = remove_entries(spotify, &playlist_id, None, |entries| {
+        policy.expired(entries, &dates, now)
+    })
+    .await;
+    for entry in removed {
+        println!("Removed {}", entry.name());
     }
 }
 
@@ -144,18 +288,61 @@ pub async fn update_everything(
     playlist_id: &str,
     num_recent_songs: usize,
     num_total_songs: usize,
+    options: &SampleOptions,
+    order: &OrderOptions,
 ) {
     clear_playlist(spotify, playlist_id).await;
     let mut recent_tracks = saved_tracks_to_tracks(
         recently_added_tracks(spotify, library, Some(num_recent_songs)).await,
     );
-    let mut rng = thread_rng();
-    let mut all_tracks = library
-        .retrieve_tracks()
-        .into_values()
-        .choose_multiple(&mut rng, num_total_songs);
+    // The recent tracks come out of the duration first and the sample fills the rest
+    let remaining = match options.duration {
+        Some(target) => {
+            recent_tracks = TrackLimit::Duration(target).apply(recent_tracks);
+            Some(target.remaining(total_duration(&recent_tracks)))
+        }
+        None => None,
+    };
+    let seed = options.seed();
+    let mut rng = seeded_rng(seed);
+    let mut all_tracks = match &options.albums {
+        // Nothing is on a cooldown here, the albums are only ranked by the strategy
+        Some(sampling) => sample_whole_albums(
+            library,
+            &Cooldown {
+                last_sampled: HashMap::new(),
+                window: Duration::zero(),
+                now: Utc::now(),
+            },
+            sampling,
+            remaining.as_ref(),
+            options,
+            &mut rng,
+        ),
+        None => {
+            // Songs already in the recent part aren't sampled again under another release
+            let recent_keys = recent_tracks
+                .iter()
+                .map(canonical_key)
+                .collect::<HashSet<_>>();
+            let pool = history_filtered_tracks(library, options)
+                .into_iter()
+                .filter(|track| !recent_keys.contains(&canonical_key(track)))
+                .collect_vec();
+            let all_tracks = sample_tracks(
+                pool,
+                &TrackLimit::new(num_total_songs, remaining),
+                options,
+                &SampleContext::new(library, options.strategy),
+                &mut rng,
+            );
+            // Recent tracks stay grouped by album, only the sampled tracks get reordered
+            order_tracks(all_tracks, order)
+        }
+    };
     recent_tracks.append(all_tracks.as_mut());
     add_tracks_to_playlist(spotify, playlist_id, recent_tracks, None).await;
+    record_seed(spotify, playlist_id, seed).await;
 }
 
 pub async fn update_weekly_sample(
@@ -163,14 +350,120 @@ pub async fn update_weekly_sample(
     library: &LibraryDatabase,
     playlist_id: &str,
     num_songs: usize,
+    options: &SampleOptions,
+    cooldown_days: i64,
+    order: &OrderOptions,
 ) {
     clear_playlist(spotify, playlist_id).await;
-    let mut rng = thread_rng();
-    let all_tracks = library
-        .retrieve_tracks()
-        .into_values()
-        .choose_multiple(&mut rng, num_songs);
+    let seed = options.seed();
+    let mut rng = seeded_rng(seed);
+    let cooldown = Cooldown {
+        last_sampled: library.retrieve_sampled(),
+        window: Duration::days(cooldown_days),
+        now: Utc::now(),
+    };
+    let all_tracks = match &options.albums {
+        Some(sampling) => sample_whole_albums(
+            library,
+            &cooldown,
+            sampling,
+            options.duration.as_ref(),
+            options,
+            &mut rng,
+        ),
+        None => {
+            let all_tracks = sample_without_repeats(
+                history_filtered_tracks(library, options),
+                &cooldown,
+                &TrackLimit::new(num_songs, options.duration),
+                options,
+                &SampleContext::new(library, options.strategy),
+                &mut rng,
+            );
+            order_tracks(all_tracks, order)
+        }
+    };
+    let track_ids = sampled_ids(&all_tracks);
     add_tracks_to_playlist(spotify, playlist_id, all_tracks, None).await;
+    library.update_sampled(track_ids, cooldown.now);
+    record_seed(spotify, playlist_id, seed).await;
+}
+
+static SEED_MARKER: &str = "rspot seed: ";
+
+// Keeps the seed at the end of the description so the playlist can be generated again
+async fn record_seed(spotify: &AuthCodeSpotify, playlist_id: &str, seed: u64) {
+    println!("Generated with seed {}", seed);
+    let playlist_id = PlaylistId::from_id(playlist_id).unwrap();
+    let description = spotify
+        .playlist(playlist_id.clone(), None, None)
+        .await
+        .unwrap()
+        .description
+        .as_deref()
+        .map(unescape_html)
+        .unwrap_or_default();
+    let description = match description.find(SEED_MARKER) {
+        Some(index) => description[..index].trim_end().to_string(),
+        None => description,
+    };
+    let description = format!("{} {}{}", description, SEED_MARKER, seed);
+
+    spotify
+        .playlist_change_detail(playlist_id, None, None, Some(description.trim()), None)
+        .await
+        .unwrap();
+}
+
+// Albums are made of the saved albums' tracks that pass the same history filter and release
+// dedupe as single tracks do. Ordering is skipped so albums are never split up
+fn sample_whole_albums(
+    library: &LibraryDatabase,
+    cooldown: &Cooldown,
+    sampling: &AlbumSampling,
+    duration: Option<&DurationTarget>,
+    options: &SampleOptions,
+    rng: &mut StdRng,
+) -> Vec<FullTrack> {
+    let saved_albums = library.retrieve_albums();
+    let album_tracks = group_album_tracks(history_filtered_tracks(library, options))
+        .into_iter()
+        .filter(|(album_id, _)| saved_albums.contains_key(album_id))
+        .collect();
+    sample_albums(
+        album_tracks,
+        cooldown,
+        sampling,
+        duration,
+        options,
+        &SampleContext::new(library, options.strategy),
+        rng,
+    )
+}
+
+// One release of each playable library recording, keeping the ones whose listening history passes
+// the filter
+fn history_filtered_tracks(library: &LibraryDatabase, options: &SampleOptions) -> Vec<FullTrack> {
+    // Sorted so seeded samples don't depend on the map order
+    let tracks = library
+        .retrieve_tracks()
+        .into_iter()
+        .sorted_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, track)| track)
+        .collect_vec();
+    let tracks = dedupe_tracks(tracks, options.release_preference)
+        .into_iter()
+        .filter(|track| track.is_playable != Some(false))
+        .collect_vec();
+    if options.history_filter.is_empty() {
+        return tracks;
+    }
+
+    let stats = library.play_stats();
+    tracks
+        .into_iter()
+        .filter(|track| options.history_filter.allows(stats.get(&track_key(track))))
+        .collect_vec()
 }
 
 pub async fn clear_playlist(spotify: &AuthCodeSpotify, playlist_id: &str) {
@@ -180,30 +473,197 @@ pub async fn clear_playlist(spotify: &AuthCodeSpotify, playlist_id: &str) {
         .unwrap();
 }
 
-pub async fn update_liked(spotify: &AuthCodeSpotify, library: &LibraryDatabase, playlist_id: &str) {
+pub async fn update_liked(
+    spotify: &AuthCodeSpotify,
+    library: &LibraryDatabase,
+    playlist_id: &str,
+    preference: ReleasePreference,
+    duration: Option<DurationTarget>,
+    order: &OrderOptions,
+) {
     // I need to think of a more elegant solution than doing 600 hard coded
-    let liked_tracks = library.retrieve_liked().into_values().collect();
+    let liked_tracks = library
+        .retrieve_liked()
+        .into_iter()
+        .sorted_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, track)| track)
+        .collect_vec();
+    let mut liked_tracks = dedupe_tracks(liked_tracks, preference);
+    if let Some(target) = duration {
+        liked_tracks = TrackLimit::Duration(target).apply(liked_tracks);
+    }
+    let liked_tracks = order_tracks(liked_tracks, order);
     clear_playlist(spotify, playlist_id).await;
     add_tracks_to_playlist(spotify, playlist_id, liked_tracks, None).await;
 }
 
-pub async fn get_playlist_tracks(
+// Fills the playlist with the library tracks matching the audio feature query. Without a sort
+// the most recently added tracks come first
+pub async fn update_smart(
+    spotify: &AuthCodeSpotify,
+    library: &LibraryDatabase,
+    playlist_id: &str,
+    query: &FeatureQuery,
+    limit: &TrackLimit,
+    order: &OrderOptions,
+) {
+    let added = library.retrieve_added();
+    let tracks = library
+        .retrieve_tracks()
+        .into_iter()
+        .sorted_by(|(a, _), (b, _)| a.cmp(b))
+        .sorted_by_key(|(id, _)| std::cmp::Reverse(added.get(id).copied()))
+        .map(|(_, track)| track)
+        .collect_vec();
+
+    let tracks = limit.apply(query.apply(tracks, &library.retrieve_features()));
+    println!("Adding {} tracks", tracks.len());
+    let tracks = order_tracks(tracks, order);
+    clear_playlist(spotify, playlist_id).await;
+    add_tracks_to_playlist(spotify, playlist_id, tracks, None).await;
+}
+
+// The latest episodes of the saved shows that haven't been listened to all the way, newest first
+pub async fn update_episodes(
+    spotify: &AuthCodeSpotify,
+    library: &LibraryDatabase,
+    playlist_id: &str,
+    num_episodes: usize,
+) {
+    let shows = library.retrieve_shows();
+    let episode_ids = library
+        .retrieve_episodes()
+        .into_values()
+        .filter(|episode| shows.contains_key(episode.show.id.id()))
+        .sorted_by(|a, b| (&b.release_date, b.id.id()).cmp(&(&a.release_date, a.id.id())))
+        .map(|episode| episode.id)
+        .collect_vec();
+
+    // Resume points in the library are from when the episode was stored, so they're fetched again
+    let mut unfinished = Vec::new();
+    for group in episode_ids.chunks(50) {
+        for episode in episodes(spotify, group, Some(library.market())).await {
+            let finished = episode
+                .resume_point
+                .as_ref()
+                .map_or(false, |resume_point| resume_point.fully_played);
+            if !finished {
+                unfinished.push(PlayableItem::Episode(episode));
+            }
+        }
+        if unfinished.len() >= num_episodes {
+            break;
+        }
+    }
+    unfinished.truncate(num_episodes);
+
+    clear_playlist(spotify, playlist_id).await;
+    add_items_to_playlist(spotify, playlist_id, unfinished, None).await;
+}
+
+// The id a track was asked for by, which is what the playlist or library holds. When a market is
+// given and the track is relinked to another release in it, the returned track has that release's
+// id and linked_from has this one
+pub fn playlist_track_id(track: &FullTrack) -> Option<TrackId<'static>> {
+    track
+        .linked_from
+        .as_ref()
+        .and_then(|link| link.id.clone())
+        .or_else(|| track.id.clone())
+}
+
+// A playlist item and where it is in the playlist. Items Spotify no longer has come back without
+// a track
+#[derive(Clone)]
+pub struct PlaylistEntry {
+    pub position: u32,
+    pub item: Option<PlayableItem>,
+    pub is_local: bool,
+}
+
+impl PlaylistEntry {
+    pub fn name(&self) -> String {
+        match &self.item {
+            Some(PlayableItem::Track(track)) => match track.artists.get(0) {
+                Some(artist) => format!("{} - {}", artist.name, track.name),
+                None => track.name.clone(),
+            },
+            Some(PlayableItem::Episode(episode)) => {
+                format!("{} - {}", episode.show.name, episode.name)
+            }
+            None => String::from("Unknown item"),
+        }
+    }
+
+    // What's at the position, the uri of a Spotify item or the key of a local file. Unavailable
+    // items have neither
+    pub fn key(&self) -> Option<String> {
+        match &self.item {
+            Some(PlayableItem::Track(track)) if self.is_local || track.is_local => {
+                Some(local_key(track))
+            }
+            Some(PlayableItem::Track(track)) => playlist_track_id(track).map(|id| id.uri()),
+            Some(PlayableItem::Episode(episode)) => Some(episode.id.uri()),
+            None => None,
+        }
+    }
+}
+
+// Tracks come back with is_playable set when a market is given
+pub async fn get_playlist_entries(
     spotify: &AuthCodeSpotify,
     playlist_id: &PlaylistId<'_>,
-) -> Vec<FullTrack> {
-    let mut tracks = Vec::new();
-    let stream = spotify.playlist_items(playlist_id.clone(), None, None);
+    market: Option<Market>,
+) -> Vec<PlaylistEntry> {
+    let mut entries = Vec::new();
+    let stream = spotify.playlist_items(playlist_id.clone(), None, market);
     pin_mut!(stream);
 
     while let Some(item) = stream.try_next().await.unwrap() {
-        if let Some(track) = item.track {
-            if let PlayableItem::Track(track) = track {
-                tracks.push(track);
-            }
-        }
+        entries.push(PlaylistEntry {
+            position: entries.len() as u32,
+            item: item.track,
+            is_local: item.is_local,
+        });
     }
 
-    tracks
+    entries
+}
+
+// Tracks and episodes in playlist order
+pub async fn get_playlist_items(
+    spotify: &AuthCodeSpotify,
+    playlist_id: &PlaylistId<'_>,
+) -> Vec<PlayableItem> {
+    get_playlist_entries(spotify, playlist_id, None)
+        .await
+        .into_iter()
+        .filter_map(|entry| entry.item)
+        .collect()
+}
+
+// Only the tracks, for the updates that work on songs
+pub async fn get_playlist_tracks(
+    spotify: &AuthCodeSpotify,
+    playlist_id: &PlaylistId<'_>,
+) -> Vec<FullTrack> {
+    get_playlist_items(spotify, playlist_id)
+        .await
+        .into_iter()
+        .filter_map(|item| match item {
+            PlayableItem::Track(track) => Some(track),
+            PlayableItem::Episode(_) => None,
+        })
+        .collect()
+}
+
+// How the songs a search finds are filtered and cut down before they're added
+pub struct SearchOptions {
+    pub history_filter: HistoryFilter,
+    pub sort_by_plays: bool,
+    pub release_preference: ReleasePreference,
+    pub features: FeatureQuery,
+    pub duration: Option<DurationTarget>,
 }
 
 pub async fn add_searched_tracks(
@@ -212,8 +672,19 @@ pub async fn add_searched_tracks(
     playlist_id: &str,
     query: &str,
     print_tracks: bool,
+    options: &SearchOptions,
+    order: &OrderOptions,
 ) {
-    let track_ids = library.search_songs(query);
+    let mut track_ids = library.search_songs(query);
+    if !options.history_filter.is_empty() || options.sort_by_plays {
+        let stats = library.play_stats();
+        track_ids.retain(|id| options.history_filter.allows(stats.get(id)));
+        if options.sort_by_plays {
+            track_ids.sort_by_key(|id| {
+                std::cmp::Reverse(stats.get(id).map_or(0, |stats| stats.play_count))
+            });
+        }
+    }
     let stored_tracks = library.retrieve_tracks();
     let mut filtered_tracks = Vec::new();
     for track in track_ids {
@@ -232,6 +703,16 @@ pub async fn add_searched_tracks(
         }
         filtered_tracks.push(stored_tracks.get(&track).unwrap().clone());
     }
+    filtered_tracks = dedupe_tracks(filtered_tracks, options.release_preference);
+    if !options.features.is_empty() {
+        filtered_tracks = options
+            .features
+            .apply(filtered_tracks, &library.retrieve_features());
+    }
+    if let Some(target) = options.duration {
+        filtered_tracks = TrackLimit::Duration(target).apply(filtered_tracks);
+    }
+    let filtered_tracks = order_tracks(filtered_tracks, order);
     clear_playlist(&spotify, playlist_id).await;
     add_tracks_to_playlist(&spotify, playlist_id, filtered_tracks, None).await;
     let _ = spotify
@@ -253,24 +734,49 @@ pub async fn add_tracks_to_playlist(
     recent_tracks: Vec<FullTrack>,
     position: Option<i32>,
 ) {
-    let recent_tracks = recent_tracks
-        .iter()
-        .map(|track| track.id.as_ref().unwrap().clone())
-        .unique()
+    let items = recent_tracks
+        .into_iter()
+        .map(PlayableItem::Track)
+        .collect_vec();
+    add_items_to_playlist(spotify, playlist_id, items, position).await;
+}
+
+// Same as adding tracks but takes episodes too
+pub async fn add_items_to_playlist(
+    spotify: &AuthCodeSpotify,
+    playlist_id: &str,
+    items: Vec<PlayableItem>,
+    position: Option<i32>,
+) {
+    // Items the market can't play would only show up greyed out
+    let (items, unplayable): (Vec<_>, Vec<_>) = items.into_iter().partition(|item| match item {
+        PlayableItem::Track(track) => track.is_playable != Some(false),
+        PlayableItem::Episode(episode) => episode.is_playable,
+    });
+    if !unplayable.is_empty() {
+        println!(
+            "Skipping {} items not playable in the market",
+            unplayable.len()
+        );
+    }
+    let items = items
+        .into_iter()
+        .filter_map(|item| match item {
+            PlayableItem::Track(track) => Some(PlayableId::Track(track.id?)),
+            PlayableItem::Episode(episode) => Some(PlayableId::Episode(episode.id)),
+        })
+        .unique_by(|id| id.uri())
         .collect_vec();
 
-    let paginated_tracks = paginate_vec(recent_tracks, 99);
+    let paginated_items = paginate_vec(items, 99);
 
     let mut count = 0;
-    for tracks in paginated_tracks {
-        // println!("Iteration {}", count);
-        // for track in track_ids_to_tracks(spotify, tracks.clone()).await {
-        //     println!("Order of tracks: {}", track.name)
-        // }
+    for items in paginated_items {
+        let num_items = items.len() as i32;
         spotify
             .playlist_add_items(
                 PlaylistId::from_id(playlist_id).unwrap(),
-                id_to_playable_ids(&tracks).into_iter(),
+                items,
                 match position {
                     Some(index) => Some(index + count),
                     None => None,
@@ -278,7 +784,7 @@ pub async fn add_tracks_to_playlist(
             )
             .await
             .unwrap();
-        count += tracks.len() as i32;
+        count += num_items;
     }
 }
 
@@ -0,0 +1,153 @@
+use chrono::{Datelike, Duration, NaiveDate, Utc};
+use clap::ValueEnum;
+use futures::stream::TryStreamExt;
+use futures_util::pin_mut;
+use itertools::Itertools;
+use rspotify::{
+    model::{ArtistId, FullTrack, SimplifiedAlbum},
+    prelude::*,
+    AuthCodeSpotify,
+};
+
+use super::{
+    conversion::albums_to_tracks,
+    decades::{parse_release_date, ReleaseDate},
+    retrieve::{albums, followed_artists},
+    storage::LibraryDatabase,
+};
+
+static NEW_RELEASES_CURSOR: &str = "new_releases";
+
+// Spotify's album groups, which say how the artist is on the release
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum ReleaseType {
+    Album,
+    Single,
+    Compilation,
+    /// Releases by other artists the artist features on
+    AppearsOn,
+}
+
+impl ReleaseType {
+    pub fn group(&self) -> &str {
+        match self {
+            ReleaseType::Album => "album",
+            ReleaseType::Single => "single",
+            ReleaseType::Compilation => "compilation",
+            ReleaseType::AppearsOn => "appears_on",
+        }
+    }
+}
+
+// The day of the last scan, releases from that day on count as new. A release only known to the
+// year or month counts when that year or month isn't over yet
+fn released_since(release: &ReleaseDate, since: NaiveDate) -> bool {
+    let since = ReleaseDate {
+        year: since.year(),
+        month: release.month.map(|_| since.month()),
+        day: release.day.map(|_| since.day()),
+    };
+    *release >= since
+}
+
+// Followed artists, plus every artist with a saved album when asked for
+async fn watched_artists(
+    spotify: &AuthCodeSpotify,
+    library: &LibraryDatabase,
+    library_artists: bool,
+) -> Vec<(ArtistId<'static>, String)> {
+    let mut artists = followed_artists(spotify)
+        .await
+        .into_iter()
+        .map(|artist| (artist.id, artist.name))
+        .collect_vec();
+    if library_artists {
+        artists.extend(
+            library
+                .retrieve_albums()
+                .into_values()
+                .flat_map(|album| album.artists)
+                .filter_map(|artist| Some((artist.id?, artist.name))),
+        );
+    }
+    artists
+        .into_iter()
+        .unique_by(|(id, _)| id.id().to_string())
+        .sorted_by(|(_, a), (_, b)| a.cmp(b))
+        .collect_vec()
+}
+
+pub async fn artist_releases(
+    spotify: &AuthCodeSpotify,
+    library: &LibraryDatabase,
+    artist_id: ArtistId<'static>,
+) -> Vec<SimplifiedAlbum> {
+    let stream = spotify.artist_albums(artist_id, None, Some(library.market()));
+    pin_mut!(stream);
+
+    let mut releases = Vec::new();
+    while let Some(album) = stream.try_next().await.unwrap() {
+        releases.push(album);
+    }
+    releases
+}
+
+// Finds the releases of the watched artists since the last scan, or the last since_days on the
+// first one, and returns their tracks newest release first along with the day of the scan
+pub async fn find_new_releases(
+    spotify: &AuthCodeSpotify,
+    library: &LibraryDatabase,
+    release_types: &[ReleaseType],
+    library_artists: bool,
+    since_days: i64,
+) -> (Vec<FullTrack>, NaiveDate) {
+    let today = Utc::now().date_naive();
+    let since = library
+        .retrieve_cursor(NEW_RELEASES_CURSOR)
+        .and_then(|cursor| NaiveDate::parse_from_str(&cursor, "%Y-%m-%d").ok())
+        .unwrap_or(today - Duration::days(since_days));
+    println!("Looking for releases since {}", since);
+
+    let groups = release_types
+        .iter()
+        .map(|release_type| release_type.group())
+        .collect_vec();
+    let mut new_releases = Vec::new();
+    for (artist_id, name) in watched_artists(spotify, library, library_artists).await {
+        for release in artist_releases(spotify, library, artist_id).await {
+            let group = release
+                .album_group
+                .as_deref()
+                .or(release.album_type.as_deref());
+            let date = release.release_date.as_deref().and_then(|date| {
+                parse_release_date(date, release.release_date_precision.as_deref())
+            });
+            let (Some(group), Some(date), Some(id)) = (group, date, release.id.clone()) else {
+                continue;
+            };
+            if groups.contains(&group) && released_since(&date, since) {
+                println!("{}: {} ({})", name, release.name, group);
+                new_releases.push((date, id));
+            }
+        }
+    }
+
+    // The same release turns up under every artist on it
+    let album_ids = new_releases
+        .into_iter()
+        .sorted_by(|(a, _), (b, _)| b.cmp(a))
+        .map(|(_, id)| id)
+        .unique_by(|id| id.id().to_string())
+        .collect_vec();
+    let albums = albums(spotify, album_ids).await;
+    let tracks = albums_to_tracks(spotify, albums, Some(library.market())).await;
+    (tracks, today)
+}
+
+// Only saved once the releases are in the playlist, so a failed run looks at them again
+pub fn save_release_scan(library: &LibraryDatabase, scanned_on: NaiveDate) {
+    library.update_cursor(
+        NEW_RELEASES_CURSOR,
+        scanned_on.format("%Y-%m-%d").to_string(),
+    );
+}
@@ -0,0 +1,153 @@
+use itertools::Itertools;
+use rspotify::{
+    model::{ItemPositions, Market, PlayableId, PlaylistId},
+    prelude::*,
+    AuthCodeSpotify, ClientResult,
+};
+use std::collections::HashSet;
+
+use super::playlists::{get_playlist_entries, PlaylistEntry};
+
+const MAX_ATTEMPTS: usize = 3;
+
+async fn snapshot_id(spotify: &AuthCodeSpotify, playlist_id: &PlaylistId<'_>) -> String {
+    spotify
+        .playlist(playlist_id.clone(), None, None)
+        .await
+        .unwrap()
+        .snapshot_id
+}
+
+// The entries along with the snapshot they're from. The snapshot is checked on both sides of
+// paging through the entries so an edit in the middle of it isn't missed, and when the playlist
+// keeps changing nothing is returned
+async fn read_playlist(
+    spotify: &AuthCodeSpotify,
+    playlist_id: &PlaylistId<'_>,
+    market: Option<Market>,
+) -> Option<(String, Vec<PlaylistEntry>)> {
+    for _ in 0..MAX_ATTEMPTS {
+        let before = snapshot_id(spotify, playlist_id).await;
+        let entries = get_playlist_entries(spotify, playlist_id, market).await;
+        if snapshot_id(spotify, playlist_id).await == before {
+            return Some((before, entries));
+        }
+    }
+    None
+}
+
+// Removes by position so only the given copies go, not every occurrence of the item. The
+// highest positions go first so the positions in later requests haven't moved yet, and each
+// request is made against the snapshot the last one left. The positions of the requests that went
+// through are returned with the result, so a failure partway still counts what was removed
+async fn remove_positions(
+    spotify: &AuthCodeSpotify,
+    playlist_id: &PlaylistId<'_>,
+    mut snapshot_id: String,
+    removals: &[(u32, PlayableId<'static>)],
+) -> (HashSet<u32>, ClientResult<()>) {
+    let removals = removals
+        .iter()
+        .sorted_by_key(|(position, _)| std::cmp::Reverse(*position))
+        .collect_vec();
+
+    let mut removed = HashSet::new();
+    for chunk in removals.chunks(100) {
+        let positions = chunk
+            .iter()
+            .into_group_map_by(|(_, id)| id.uri())
+            .into_values()
+            .map(|removals| {
+                let positions = removals.iter().map(|(position, _)| *position).collect_vec();
+                (removals[0].1.clone(), positions)
+            })
+            .collect_vec();
+        let items = positions
+            .iter()
+            .map(|(id, positions)| ItemPositions {
+                id: id.clone(),
+                positions,
+            })
+            .collect_vec();
+
+        match spotify
+            .playlist_remove_specific_occurrences_of_items(
+                playlist_id.clone(),
+                items,
+                Some(&snapshot_id),
+            )
+            .await
+        {
+            Ok(result) => snapshot_id = result.snapshot_id,
+            Err(err) => return (removed, Err(err)),
+        }
+        removed.extend(chunk.iter().map(|(position, _)| *position));
+    }
+    (removed, Ok(()))
+}
+
+// Removes the items select picks out of the playlist and returns the entries that were removed.
+// The playlist is read again after the write, and when it isn't what the removal should have left
+// because it changed in the meantime, select is run on the new state and tried again
+pub async fn remove_entries<F>(
+    spotify: &AuthCodeSpotify,
+    playlist_id: &PlaylistId<'_>,
+    market: Option<Market>,
+    select: F,
+) -> Vec<PlaylistEntry>
+where
+    F: Fn(&[PlaylistEntry]) -> Vec<(u32, PlayableId<'static>)>,
+{
+    let mut removed = Vec::new();
+    for attempt in 1..=MAX_ATTEMPTS {
+        let Some((snapshot_id, entries)) = read_playlist(spotify, playlist_id, market).await else {
+            println!("The playlist kept changing while reading it, giving up removing items");
+            return removed;
+        };
+        let removals = select(&entries);
+        if removals.is_empty() {
+            return removed;
+        }
+
+        let positions = removals
+            .iter()
+            .map(|(position, _)| *position)
+            .collect::<HashSet<_>>();
+        let expected = entries
+            .iter()
+            .filter(|entry| !positions.contains(&entry.position))
+            .map(PlaylistEntry::key)
+            .collect_vec();
+
+        let (done, result) = remove_positions(spotify, playlist_id, snapshot_id, &removals).await;
+        removed.extend(
+            entries
+                .into_iter()
+                .filter(|entry| done.contains(&entry.position)),
+        );
+        if let Err(err) = result {
+            println!(
+                "Couldn't remove items, trying again ({}/{}): {}",
+                attempt, MAX_ATTEMPTS, err
+            );
+            continue;
+        }
+
+        let after = read_playlist(spotify, playlist_id, market).await;
+        if after.map_or(false, |(_, after)| {
+            after.iter().map(PlaylistEntry::key).collect_vec() == expected
+        }) {
+            return removed;
+        }
+        println!(
+            "The playlist changed while removing items, trying again ({}/{})",
+            attempt, MAX_ATTEMPTS
+        );
+    }
+
+    println!(
+        "Gave up removing items after {} attempts, the playlist may need checking",
+        MAX_ATTEMPTS
+    );
+    removed
+}
@@ -0,0 +1,255 @@
+use chrono::{DateTime, Duration, Utc};
+use itertools::Itertools;
+use rspotify::{
+    model::{FullTrack, PlayableId, PlayableItem},
+    prelude::*,
+};
+use std::collections::{HashMap, HashSet};
+
+use super::{
+    duration::TrackLimit,
+    playlists::{playlist_track_id, PlaylistEntry},
+    storage::LibraryDatabase,
+};
+
+// Which songs the recently added playlist keeps. A song goes once it's past the track limit, past
+// the first max_albums albums or was added longer than max_age ago. Liked singles added within
+// liked_singles_age stay whatever the other rules say
+#[derive(Clone, Copy, Debug)]
+pub struct RetentionPolicy {
+    pub limit: TrackLimit,
+    pub max_age: Option<Duration>,
+    pub max_albums: Option<usize>,
+    // Without this the cut is pushed back to the end of the album it falls in
+    pub split_albums: bool,
+    pub liked_singles_age: Option<Duration>,
+}
+
+// When the library's songs were added, and which of them are liked singles, liked songs whose
+// album isn't saved
+pub struct AddedDates {
+    added_at: HashMap<String, DateTime<Utc>>,
+    liked_singles: HashSet<String>,
+}
+
+impl AddedDates {
+    pub fn new(library: &LibraryDatabase) -> AddedDates {
+        let albums = library.retrieve_albums();
+        let liked_singles = library
+            .retrieve_liked()
+            .into_iter()
+            .filter(|(_, track)| {
+                track
+                    .album
+                    .id
+                    .as_ref()
+                    .map_or(true, |album_id| !albums.contains_key(album_id.id()))
+            })
+            .map(|(id, _)| id)
+            .collect();
+        AddedDates {
+            added_at: library.retrieve_added(),
+            liked_singles,
+        }
+    }
+
+    fn added_at(&self, track: &FullTrack) -> Option<DateTime<Utc>> {
+        self.added_at.get(track.id.as_ref()?.id()).copied()
+    }
+
+    fn is_liked_single(&self, track: &FullTrack) -> bool {
+        track
+            .id
+            .as_ref()
+            .map_or(false, |id| self.liked_singles.contains(id.id()))
+    }
+}
+
+// Local files have no album id, so their album name is compared instead
+fn same_album(a: &FullTrack, b: &FullTrack) -> bool {
+    match (&a.album.id, &b.album.id) {
+        (Some(a_id), Some(b_id)) => a_id == b_id,
+        (None, None) => a.album.name == b.album.name,
+        _ => false,
+    }
+}
+
+impl RetentionPolicy {
+    // Songs the library has no date for are never too old
+    fn is_too_old(&self, track: &FullTrack, dates: &AddedDates, now: DateTime<Utc>) -> bool {
+        match (self.max_age, dates.added_at(track)) {
+            (Some(max_age), Some(added_at)) => now - added_at > max_age,
+            _ => false,
+        }
+    }
+
+    fn is_protected(&self, track: &FullTrack, dates: &AddedDates, now: DateTime<Utc>) -> bool {
+        match (self.liked_singles_age, dates.added_at(track)) {
+            (Some(min_age), Some(added_at)) => {
+                dates.is_liked_single(track) && now - added_at < min_age
+            }
+            _ => false,
+        }
+    }
+
+    // Which tracks are too old. Without split_albums an album only goes once all of its tracks are
+    // too old, the same way the cut is pushed back to the end of an album
+    fn too_old(&self, tracks: &[FullTrack], dates: &AddedDates, now: DateTime<Utc>) -> Vec<bool> {
+        let too_old = tracks
+            .iter()
+            .map(|track| self.is_too_old(track, dates, now))
+            .collect_vec();
+        if self.split_albums {
+            return too_old;
+        }
+
+        let mut album_too_old = Vec::with_capacity(tracks.len());
+        let mut start = 0;
+        while start < tracks.len() {
+            let mut end = start + 1;
+            while end < tracks.len() && same_album(&tracks[end - 1], &tracks[end]) {
+                end += 1;
+            }
+            let all_too_old = too_old[start..end].iter().all(|too_old| *too_old);
+            album_too_old.extend(std::iter::repeat(all_too_old).take(end - start));
+            start = end;
+        }
+        album_too_old
+    }
+
+    // Where the track limit and album count cut the playlist's tracks
+    fn cut(&self, tracks: &[FullTrack]) -> usize {
+        // Always keeps the first track so there's an album to finish
+        let mut cut = self.limit.leading_tracks(tracks).max(1).min(tracks.len());
+        if let Some(max_albums) = self.max_albums {
+            let mut num_albums = 0;
+            let album_end = tracks
+                .iter()
+                .enumerate()
+                .position(|(index, track)| {
+                    if index == 0 || !same_album(&tracks[index - 1], track) {
+                        num_albums += 1;
+                    }
+                    num_albums > max_albums
+                })
+                .unwrap_or(tracks.len());
+            // Still keeps the first album when max_albums is 0
+            cut = cut.min(album_end).max(1).min(tracks.len());
+        }
+
+        if !self.split_albums {
+            while cut > 0 && cut < tracks.len() && same_album(&tracks[cut - 1], &tracks[cut]) {
+                cut += 1;
+            }
+        }
+        cut
+    }
+
+    // The playlist's songs the policy lets go of. Positions are the playlist's so episodes and
+    // unavailable items in between don't throw them off, and local files are left in place since
+    // they can't be removed through the api
+    pub fn expired(
+        &self,
+        entries: &[PlaylistEntry],
+        dates: &AddedDates,
+        now: DateTime<Utc>,
+    ) -> Vec<(u32, PlayableId<'static>)> {
+        let (positions, tracks): (Vec<u32>, Vec<FullTrack>) = entries
+            .iter()
+            .filter_map(|entry| match &entry.item {
+                Some(PlayableItem::Track(track)) => Some((entry.position, track.clone())),
+                _ => None,
+            })
+            .unzip();
+        let cut = self.cut(&tracks);
+        let too_old = self.too_old(&tracks, dates, now);
+
+        positions
+            .into_iter()
+            .zip(&tracks)
+            .enumerate()
+            .filter(|(index, _)| *index >= cut || too_old[*index])
+            .filter(|(_, (_, track))| !self.is_protected(track, dates, now))
+            .filter_map(|(_, (position, track))| match playlist_track_id(track) {
+                Some(id) if !track.is_local => Some((position, PlayableId::Track(id))),
+                _ => None,
+            })
+            .collect_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::fixtures::track;
+
+    fn policy(limit: usize, max_albums: Option<usize>, split_albums: bool) -> RetentionPolicy {
+        RetentionPolicy {
+            limit: TrackLimit::Count(limit),
+            max_age: None,
+            max_albums,
+            split_albums,
+            liked_singles_age: None,
+        }
+    }
+
+    // Two tracks from A, two from B and one from C
+    fn tracks() -> Vec<FullTrack> {
+        [
+            ("a1", "A"),
+            ("a2", "A"),
+            ("b1", "B"),
+            ("b2", "B"),
+            ("c1", "C"),
+        ]
+        .iter()
+        .map(|(id, album)| track(id, id, "Artist", album, 200000))
+        .collect_vec()
+    }
+
+    #[test]
+    fn cut_finishes_the_album() {
+        assert_eq!(policy(3, None, false).cut(&tracks()), 4);
+        assert_eq!(policy(3, None, true).cut(&tracks()), 3);
+        assert_eq!(policy(10, None, false).cut(&tracks()), 5);
+    }
+
+    #[test]
+    fn cut_stops_at_max_albums() {
+        assert_eq!(policy(10, Some(1), false).cut(&tracks()), 2);
+        assert_eq!(policy(10, Some(2), true).cut(&tracks()), 4);
+        assert_eq!(policy(1, Some(2), false).cut(&tracks()), 2);
+    }
+
+    #[test]
+    fn cut_keeps_an_album_with_no_albums_allowed() {
+        assert_eq!(policy(10, Some(0), false).cut(&tracks()), 2);
+        assert_eq!(policy(10, Some(0), true).cut(&tracks()), 1);
+        assert_eq!(policy(0, None, true).cut(&tracks()), 1);
+        assert_eq!(policy(10, Some(0), false).cut(&[]), 0);
+    }
+
+    #[test]
+    fn albums_age_out_together() {
+        let now = Utc::now();
+        let dates = AddedDates {
+            added_at: [("a1", 40), ("a2", 40), ("b1", 40), ("b2", 5), ("c1", 40)]
+                .iter()
+                .map(|(id, days)| (id.to_string(), now - Duration::days(*days)))
+                .collect(),
+            liked_singles: HashSet::new(),
+        };
+        let mut policy = policy(10, None, false);
+        policy.max_age = Some(Duration::days(30));
+        assert_eq!(
+            policy.too_old(&tracks(), &dates, now),
+            [true, true, false, false, true]
+        );
+
+        policy.split_albums = true;
+        assert_eq!(
+            policy.too_old(&tracks(), &dates, now),
+            [true, true, true, false, true]
+        );
+    }
+}
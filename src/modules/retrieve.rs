@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::future::Future;
 
 use chrono::{DateTime, Utc};
 use futures::stream::TryStreamExt;
@@ -7,23 +8,24 @@ use futures_util::pin_mut;
 use itertools::Itertools;
 use rspotify::{
     model::{
-        AlbumId, ArtistId, FullAlbum, FullTrack, PlayableItem, PlaylistId, SavedAlbum, SavedTrack,
-        TrackId,
+        AlbumId, ArtistId, AudioFeatures, EpisodeId, FullAlbum, FullArtist, FullEpisode, FullTrack,
+        Market, PlayHistory, PlayableItem, PlaylistId, SavedAlbum, SavedTrack, Show, ShowId,
+        TimeLimits, TrackId,
     },
     prelude::*,
-    AuthCodeSpotify,
+    AuthCodeSpotify, ClientResult,
 };
 
 use crate::modules::conversion;
 
-use super::storage::LibraryDatabase;
+use super::{local::track_key, storage::LibraryDatabase};
 
 pub async fn recently_added_albums(
     spotify: &AuthCodeSpotify,
     library: &LibraryDatabase,
     max_songs: Option<usize>,
 ) -> Vec<SavedAlbum> {
-    let stream = spotify.current_user_saved_albums(None);
+    let stream = spotify.current_user_saved_albums(Some(library.market()));
 
     pin_mut!(stream);
 
@@ -52,21 +54,22 @@ pub async fn recently_added_albums(
     recent_albums
 }
 
+// Tracks and episodes, items Spotify no longer has are left out
 pub async fn playlist_items(
     spotify: &AuthCodeSpotify,
     playlist_id: PlaylistId<'_>,
-) -> Vec<FullTrack> {
+) -> Vec<PlayableItem> {
     let stream = spotify.playlist_items(playlist_id, None, None);
 
     pin_mut!(stream);
 
-    let mut tracks = Vec::new();
+    let mut items = Vec::new();
     while let Some(item) = stream.try_next().await.unwrap() {
-        if let Some(PlayableItem::Track(track)) = item.track {
-            tracks.push(track);
+        if let Some(playable) = item.track {
+            items.push(playable);
         }
     }
-    tracks
+    items
 }
 
 pub async fn recently_added_tracks(
@@ -76,7 +79,8 @@ pub async fn recently_added_tracks(
 ) -> Vec<SavedTrack> {
     let recent_albums = recently_added_albums(spotify, library, max_songs).await;
     let mut recent_album_tracks =
-        conversion::saved_albums_to_saved_tracks(spotify, recent_albums).await;
+        conversion::saved_albums_to_saved_tracks(spotify, recent_albums, Some(library.market()))
+            .await;
     let latest_track = recent_album_tracks
         .iter()
         .min_by_key(|saved_track| saved_track.added_at);
@@ -92,8 +96,9 @@ pub async fn recently_added_tracks(
         .iter()
         .map(|track| track.track.clone())
         .collect_vec();
+    // Local files can't be added to playlists through the api
     for liked_track in liked_tracks {
-        if !full_track_list.contains(&liked_track.track) {
+        if !liked_track.track.is_local && !full_track_list.contains(&liked_track.track) {
             recent_album_tracks.push(liked_track.clone());
         }
     }
@@ -145,24 +150,24 @@ pub async fn recently_liked_tracks(
     library: &LibraryDatabase,
     latest_time: Option<&DateTime<Utc>>,
 ) -> Vec<SavedTrack> {
-    let stream = spotify.current_user_saved_tracks(None);
+    let stream = spotify.current_user_saved_tracks(Some(library.market()));
     pin_mut!(stream);
 
     let current_tracks = library.retrieve_liked();
+    let local_tracks = library.retrieve_local();
     let mut liked_tracks = Vec::new();
     while let Some(item) = stream.try_next().await.unwrap() {
-        if item.track.is_local {
-            continue;
+        let key = track_key(&item.track);
+        let is_stored = if item.track.is_local {
+            local_tracks.contains_key(&key)
         } else {
-            let id = item.track.id.as_ref().unwrap().id();
-
-            if !current_tracks.contains_key(id.clone())
-                || item.added_at > latest_time.unwrap_or(&Utc::now()).to_owned()
-            {
-                liked_tracks.push(item);
-            } else {
-                break;
-            }
+            current_tracks.contains_key(&key)
+        };
+
+        if !is_stored || item.added_at > latest_time.unwrap_or(&Utc::now()).to_owned() {
+            liked_tracks.push(item);
+        } else {
+            break;
         }
     }
 
@@ -186,18 +191,161 @@ pub async fn get_all_tracks(spotify: &AuthCodeSpotify) -> Vec<FullTrack> {
 
     let mut tracks = Vec::new();
     while let Some(item) = stream.try_next().await.unwrap() {
-        if item.track.is_local {
-            continue;
-        } else {
-            tracks.push(item.track);
-        }
+        tracks.push(item.track);
     }
 
-    let mut all_tracks = conversion::albums_to_tracks(spotify, get_all_albums(spotify).await).await;
+    let mut all_tracks =
+        conversion::albums_to_tracks(spotify, get_all_albums(spotify).await, None).await;
     tracks.append(all_tracks.as_mut());
     tracks
 }
 
+// Spotify only keeps the last 50 plays, so anything older than that is gone
+pub async fn recently_played(
+    spotify: &AuthCodeSpotify,
+    after: Option<DateTime<Utc>>,
+) -> ClientResult<Vec<PlayHistory>> {
+    let page = spotify
+        .current_user_recently_played(Some(50), after.map(TimeLimits::After))
+        .await?;
+    Ok(page.items)
+}
+
+// Fetches ids in batches of at most batch_size. An id the api can't return comes back as null and
+// fails its whole batch, so a failed batch is fetched again one id at a time and the ones that
+// fail on their own are skipped
+pub async fn fetch_in_batches<I, T, F, Fut>(
+    ids: &[I],
+    batch_size: usize,
+    kind: &str,
+    fetch: F,
+) -> Vec<T>
+where
+    I: Id + Clone,
+    F: Fn(Vec<I>) -> Fut,
+    Fut: Future<Output = ClientResult<Vec<T>>>,
+{
+    let mut fetched = Vec::new();
+    for group in ids.chunks(batch_size) {
+        if let Ok(mut batch) = fetch(group.to_vec()).await {
+            fetched.append(&mut batch);
+            continue;
+        }
+        for id in group {
+            match fetch(vec![id.clone()]).await {
+                Ok(mut single) => fetched.append(&mut single),
+                Err(err) => println!("Couldn't fetch {} {}: {}", kind, id.id(), err),
+            }
+        }
+    }
+    fetched
+}
+
+// Fetches in batches of 100, the most the endpoint takes
+pub async fn audio_features(
+    spotify: &AuthCodeSpotify,
+    track_ids: Vec<TrackId<'static>>,
+) -> Vec<AudioFeatures> {
+    fetch_in_batches(
+        &track_ids,
+        100,
+        "audio features for",
+        move |ids| async move {
+            spotify
+                .tracks_features(ids)
+                .await
+                .map(Option::unwrap_or_default)
+        },
+    )
+    .await
+}
+
+pub async fn followed_artists(spotify: &AuthCodeSpotify) -> Vec<FullArtist> {
+    let mut artists = Vec::new();
+    let mut after: Option<String> = None;
+    loop {
+        let page = spotify
+            .current_user_followed_artists(after.as_deref(), Some(50))
+            .await
+            .unwrap();
+        artists.extend(page.items);
+        after = page.cursors.and_then(|cursors| cursors.after);
+        if after.is_none() {
+            break;
+        }
+    }
+    artists
+}
+
+pub async fn artists(
+    spotify: &AuthCodeSpotify,
+    artist_ids: Vec<ArtistId<'static>>,
+) -> Vec<FullArtist> {
+    let mut artists = Vec::new();
+    for group in artist_ids.chunks(50) {
+        let mut batch = spotify.artists(group.to_vec()).await.unwrap();
+        artists.append(&mut batch);
+    }
+    artists
+}
+
+// Fetches in batches of 20, the most the endpoint takes
+pub async fn albums(spotify: &AuthCodeSpotify, album_ids: Vec<AlbumId<'static>>) -> Vec<FullAlbum> {
+    let mut albums = Vec::new();
+    for group in album_ids.chunks(20) {
+        let mut batch = spotify.albums(group.to_vec()).await.unwrap();
+        albums.append(&mut batch);
+    }
+    albums
+}
+
+pub async fn saved_shows(spotify: &AuthCodeSpotify) -> Vec<Show> {
+    let stream = spotify.get_saved_show();
+    pin_mut!(stream);
+
+    let mut shows = Vec::new();
+    while let Some(show) = stream.try_next().await.unwrap() {
+        shows.push(show);
+    }
+    shows
+}
+
+// The most episodes taken from a show in one update, so a newly saved show with a long back
+// catalogue only has its latest episodes fetched
+const MAX_NEW_EPISODES: usize = 50;
+
+// Episodes come newest first, so the show is only read up to the first one already stored
+pub async fn new_show_episodes(
+    spotify: &AuthCodeSpotify,
+    library: &LibraryDatabase,
+    show_id: ShowId<'static>,
+    stored: &HashMap<String, FullEpisode>,
+) -> Vec<EpisodeId<'static>> {
+    let stream = spotify.get_shows_episodes(show_id, Some(library.market()));
+    pin_mut!(stream);
+
+    let mut episode_ids = Vec::new();
+    while let Some(episode) = stream.try_next().await.unwrap() {
+        if stored.contains_key(episode.id.id()) || episode_ids.len() >= MAX_NEW_EPISODES {
+            break;
+        }
+        episode_ids.push(episode.id);
+    }
+    episode_ids
+}
+
+// Fetches in batches of 50, the most the endpoint takes
+pub async fn episodes(
+    spotify: &AuthCodeSpotify,
+    episode_ids: &[EpisodeId<'static>],
+    market: Option<Market>,
+) -> Vec<FullEpisode> {
+    fetch_in_batches(episode_ids, 50, "episode", move |ids| {
+        spotify.get_several_episodes(ids, market)
+    })
+    .await
+}
+
 pub async fn print_album(spotify: &AuthCodeSpotify, album: &str) {
     let album = spotify
         .album(AlbumId::from_id(album).unwrap())
@@ -0,0 +1,571 @@
+use chrono::{DateTime, Duration, Utc};
+use clap::ValueEnum;
+use itertools::Itertools;
+use rand::{rngs::StdRng, seq::SliceRandom, thread_rng, Rng, SeedableRng};
+use rspotify::{model::FullTrack, prelude::*};
+use std::collections::HashMap;
+
+use super::{
+    dedupe::ReleasePreference,
+    duration::{format_duration, total_duration, DurationTarget, TrackLimit},
+    history::{HistoryFilter, PlayStats},
+    local::track_key,
+    storage::LibraryDatabase,
+};
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum SamplingStrategy {
+    /// Every track is equally likely
+    Uniform,
+    /// Recently added tracks are more likely, halving every half life
+    Recency,
+    /// Tracks played less often are more likely
+    InversePlays,
+    /// Popular tracks are more likely
+    Popularity,
+    /// Each decade gets an equal share
+    Decade,
+    /// Each genre gets an equal share
+    Genre,
+}
+
+pub struct SampleOptions {
+    pub strategy: SamplingStrategy,
+    pub half_life_days: f64,
+    pub history_filter: HistoryFilter,
+    pub release_preference: ReleasePreference,
+    pub seed: Option<u64>,
+    pub duration: Option<DurationTarget>,
+    pub albums: Option<AlbumSampling>,
+}
+
+// Samples whole albums instead of single tracks, until either this or the duration is reached
+pub struct AlbumSampling {
+    pub num_albums: Option<usize>,
+}
+
+impl SampleOptions {
+    // The seed used for this run, picking a random one when none was given
+    pub fn seed(&self) -> u64 {
+        self.seed.unwrap_or_else(|| thread_rng().gen())
+    }
+}
+
+// When each track was last sampled, and how long it's kept out of samples after that
+pub struct Cooldown {
+    pub last_sampled: HashMap<String, DateTime<Utc>>,
+    pub window: Duration,
+    pub now: DateTime<Utc>,
+}
+
+impl Cooldown {
+    fn is_over(&self, sampled_at: DateTime<Utc>) -> bool {
+        self.now - sampled_at >= self.window
+    }
+}
+
+// The same seed and input always produce the same sample, so tracks are put in id order first
+pub fn seeded_rng(seed: u64) -> StdRng {
+    StdRng::seed_from_u64(seed)
+}
+
+// The library data the strategies weigh tracks by, only loaded when the strategy needs it
+pub struct SampleContext {
+    added_at: HashMap<String, DateTime<Utc>>,
+    play_stats: HashMap<String, PlayStats>,
+    genres: HashMap<String, Vec<String>>,
+}
+
+impl SampleContext {
+    pub fn new(library: &LibraryDatabase, strategy: SamplingStrategy) -> SampleContext {
+        SampleContext {
+            added_at: match strategy {
+                SamplingStrategy::Recency => library.retrieve_added(),
+                _ => HashMap::new(),
+            },
+            play_stats: match strategy {
+                SamplingStrategy::InversePlays => library.play_stats(),
+                _ => HashMap::new(),
+            },
+            genres: match strategy {
+                SamplingStrategy::Genre => library.track_genres(),
+                _ => HashMap::new(),
+            },
+        }
+    }
+}
+
+pub fn release_year(track: &FullTrack) -> Option<i32> {
+    track.album.release_date.as_ref()?.get(..4)?.parse().ok()
+}
+
+// Weighted sampling without replacement, each track gets the key u^(1/weight) and the largest
+// keys win (Efraimidis-Spirakis). The key is taken in log space, ln(u) / weight, since u^(1/weight)
+// underflows to zero for small weights and the ties stop it being weighted at all
+fn weighted_sample<R: Rng>(
+    tracks: Vec<FullTrack>,
+    num_songs: usize,
+    weight: impl Fn(&FullTrack) -> f64,
+    rng: &mut R,
+) -> Vec<FullTrack> {
+    tracks
+        .into_iter()
+        .map(|track| {
+            let weight = weight(&track).max(f64::MIN_POSITIVE);
+            // 1 - u so it's never ln(0)
+            ((1.0 - rng.gen::<f64>()).ln() / weight, track)
+        })
+        .sorted_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap())
+        .take(num_songs)
+        .map(|(_, track)| track)
+        .collect_vec()
+}
+
+// Shuffles each group then takes one track from every group in turn, so small groups aren't
+// drowned out by big ones
+fn stratified_sample<R: Rng>(
+    tracks: Vec<FullTrack>,
+    num_songs: usize,
+    key: impl Fn(&FullTrack) -> String,
+    rng: &mut R,
+) -> Vec<FullTrack> {
+    let mut groups = tracks.into_iter().into_group_map_by(|track| key(track));
+    // Map order changes between runs, so groups are shuffled in key order to keep seeds working
+    let mut keys = groups.keys().cloned().sorted().collect_vec();
+    for key in &keys {
+        groups.get_mut(key).unwrap().shuffle(rng);
+    }
+
+    let mut chosen = Vec::new();
+    while chosen.len() < num_songs && !keys.is_empty() {
+        keys.shuffle(rng);
+        for key in &keys {
+            if chosen.len() == num_songs {
+                break;
+            }
+            if let Some(track) = groups.get_mut(key).unwrap().pop() {
+                chosen.push(track);
+            }
+        }
+        keys.retain(|key| !groups[key].is_empty());
+    }
+    chosen
+}
+
+pub fn sample_tracks<R: Rng>(
+    tracks: Vec<FullTrack>,
+    limit: &TrackLimit,
+    options: &SampleOptions,
+    context: &SampleContext,
+    rng: &mut R,
+) -> Vec<FullTrack> {
+    limit.apply(rank_tracks(
+        tracks,
+        limit.max_tracks(),
+        options,
+        context,
+        rng,
+    ))
+}
+
+// Puts the first num_songs tracks of the sample in the order the strategy picked them
+fn rank_tracks<R: Rng>(
+    tracks: Vec<FullTrack>,
+    num_songs: usize,
+    options: &SampleOptions,
+    context: &SampleContext,
+    rng: &mut R,
+) -> Vec<FullTrack> {
+    match options.strategy {
+        SamplingStrategy::Uniform => {
+            let mut tracks = tracks;
+            tracks.shuffle(rng);
+            tracks.truncate(num_songs);
+            tracks
+        }
+        SamplingStrategy::Recency => {
+            // Ages are measured from the newest track rather than now so a seed gives the same
+            // sample on a different day
+            let newest = context
+                .added_at
+                .values()
+                .max()
+                .copied()
+                .unwrap_or_else(Utc::now);
+            // Tracks without a stored added date are treated as the oldest in the library
+            let oldest = context.added_at.values().min().copied().unwrap_or(newest);
+            weighted_sample(
+                tracks,
+                num_songs,
+                |track| {
+                    let added_at = context
+                        .added_at
+                        .get(&track_key(track))
+                        .copied()
+                        .unwrap_or(oldest);
+                    let age_days = (newest - added_at).num_hours() as f64 / 24.0;
+                    0.5_f64.powf(age_days / options.half_life_days)
+                },
+                rng,
+            )
+        }
+        SamplingStrategy::InversePlays => weighted_sample(
+            tracks,
+            num_songs,
+            |track| {
+                let plays = context
+                    .play_stats
+                    .get(&track_key(track))
+                    .map_or(0, |stats| stats.play_count);
+                1.0 / (1.0 + plays as f64)
+            },
+            rng,
+        ),
+        SamplingStrategy::Popularity => weighted_sample(
+            tracks,
+            num_songs,
+            |track| track.popularity as f64 + 1.0,
+            rng,
+        ),
+        SamplingStrategy::Decade => stratified_sample(
+            tracks,
+            num_songs,
+            |track| match release_year(track) {
+                Some(year) => format!("{}s", year / 10 * 10),
+                None => String::from("Unknown"),
+            },
+            rng,
+        ),
+        SamplingStrategy::Genre => stratified_sample(
+            tracks,
+            num_songs,
+            |track| {
+                context
+                    .genres
+                    .get(&track_key(track))
+                    .and_then(|genres| genres.first())
+                    .cloned()
+                    .unwrap_or_else(|| String::from("Unknown"))
+            },
+            rng,
+        ),
+    }
+}
+
+// Samples tracks that have never been sampled first, then the ones sampled longest ago, so the
+// whole library gets cycled through before anything repeats. Tracks sampled within the cooldown
+// are only used when there isn't anything else left
+pub fn sample_without_repeats<R: Rng>(
+    tracks: Vec<FullTrack>,
+    cooldown: &Cooldown,
+    limit: &TrackLimit,
+    options: &SampleOptions,
+    context: &SampleContext,
+    rng: &mut R,
+) -> Vec<FullTrack> {
+    let last_sampled = &cooldown.last_sampled;
+    let (never_sampled, mut sampled): (Vec<_>, Vec<_>) = tracks
+        .into_iter()
+        .partition(|track| !last_sampled.contains_key(&track_key(track)));
+
+    let mut chosen = sample_tracks(never_sampled, limit, options, context, rng);
+    if limit.is_reached(&chosen) {
+        return chosen;
+    }
+
+    // Shuffling before the stable sort keeps tracks sampled in the same week in random order
+    sampled.shuffle(rng);
+    sampled.sort_by_key(|track| last_sampled[&track_key(track)]);
+
+    let (cooled_down, cooling): (Vec<_>, Vec<_>) = sampled
+        .into_iter()
+        .partition(|track| cooldown.is_over(last_sampled[&track_key(track)]));
+
+    limit.fill(&mut chosen, cooled_down);
+    if limit.is_reached(&chosen) {
+        return chosen;
+    }
+
+    let num_outside = chosen.len();
+    limit.fill(&mut chosen, cooling);
+    println!(
+        "Only {} tracks are outside the cooldown, reusing {} recently sampled tracks",
+        num_outside,
+        chosen.len() - num_outside
+    );
+    chosen
+}
+
+// Stored tracks of each album in disc and track order
+pub fn group_album_tracks(tracks: Vec<FullTrack>) -> HashMap<String, Vec<FullTrack>> {
+    let mut albums = tracks
+        .into_iter()
+        .filter(|track| track.album.id.is_some())
+        .into_group_map_by(|track| track.album.id.as_ref().unwrap().id().to_string());
+    for tracks in albums.values_mut() {
+        tracks.sort_by_key(|track| (track.disc_number, track.track_number));
+    }
+    albums
+}
+
+// When an album was last sampled, going by its most recently sampled track
+fn album_last_sampled(
+    tracks: &[FullTrack],
+    last_sampled: &HashMap<String, DateTime<Utc>>,
+) -> Option<DateTime<Utc>> {
+    tracks
+        .iter()
+        .filter_map(|track| last_sampled.get(&track_key(track)))
+        .max()
+        .copied()
+}
+
+// The album version of sample_without_repeats. Albums never sampled come first in the order the
+// strategy ranks their first tracks, then the ones sampled longest ago, with albums still in the
+// cooldown last. Albums are taken in turn, skipping any album that would go past the duration and
+// its tolerance, and each album's tracks stay together in track order
+pub fn sample_albums<R: Rng>(
+    album_tracks: HashMap<String, Vec<FullTrack>>,
+    cooldown: &Cooldown,
+    sampling: &AlbumSampling,
+    duration: Option<&DurationTarget>,
+    options: &SampleOptions,
+    context: &SampleContext,
+    rng: &mut R,
+) -> Vec<FullTrack> {
+    let last_sampled = &cooldown.last_sampled;
+    // Sorted so seeded samples don't depend on the map order
+    let (never_sampled, mut sampled): (Vec<_>, Vec<_>) = album_tracks
+        .into_iter()
+        .sorted_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, tracks)| tracks)
+        .filter(|tracks| !tracks.is_empty())
+        .partition(|tracks| album_last_sampled(tracks, last_sampled).is_none());
+
+    let num_never_sampled = never_sampled.len();
+    let first_tracks = never_sampled
+        .iter()
+        .map(|tracks| tracks[0].clone())
+        .collect_vec();
+    let mut by_first_track = never_sampled
+        .into_iter()
+        .map(|tracks| (track_key(&tracks[0]), tracks))
+        .collect::<HashMap<_, _>>();
+    let ranked = rank_tracks(first_tracks, num_never_sampled, options, context, rng)
+        .iter()
+        .filter_map(|track| by_first_track.remove(&track_key(track)))
+        .collect_vec();
+
+    // Shuffling before the stable sort keeps albums sampled in the same week in random order
+    sampled.shuffle(rng);
+    sampled.sort_by_key(|tracks| album_last_sampled(tracks, last_sampled));
+    let (cooled_down, cooling): (Vec<_>, Vec<_>) = sampled
+        .into_iter()
+        .partition(|tracks| cooldown.is_over(album_last_sampled(tracks, last_sampled).unwrap()));
+    let num_outside = ranked.len() + cooled_down.len();
+
+    let mut chosen = Vec::new();
+    let mut num_albums = 0;
+    let mut num_reused = 0;
+    let mut total = Duration::zero();
+    for (index, tracks) in ranked
+        .into_iter()
+        .chain(cooled_down)
+        .chain(cooling)
+        .enumerate()
+    {
+        if sampling.num_albums.map_or(false, |max| num_albums >= max)
+            || duration.map_or(false, |target| target.is_reached(total))
+        {
+            break;
+        }
+
+        let length = total_duration(&tracks);
+        if duration.map_or(false, |target| !target.allows(total + length)) {
+            continue;
+        }
+
+        let album = &tracks[0].album;
+        println!(
+            "Adding album {} - {}",
+            album
+                .artists
+                .get(0)
+                .map(|artist| artist.name.as_str())
+                .unwrap_or_default(),
+            album.name
+        );
+        if index >= num_outside {
+            num_reused += 1;
+        }
+        chosen.extend(tracks);
+        num_albums += 1;
+        total = total + length;
+    }
+
+    if num_reused > 0 {
+        println!(
+            "Only {} albums are outside the cooldown, reusing {} recently sampled albums",
+            num_albums - num_reused,
+            num_reused
+        );
+    }
+    println!("Sampled {} albums, {}", num_albums, format_duration(total));
+    chosen
+}
+
+pub fn sampled_ids(tracks: &[FullTrack]) -> Vec<String> {
+    tracks.iter().map(track_key).collect_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::fixtures::track;
+    use chrono::TimeZone;
+
+    fn options() -> SampleOptions {
+        SampleOptions {
+            strategy: SamplingStrategy::Uniform,
+            half_life_days: 30.0,
+            history_filter: HistoryFilter::default(),
+            release_preference: ReleasePreference::default(),
+            seed: None,
+            duration: None,
+            albums: None,
+        }
+    }
+
+    fn context() -> SampleContext {
+        SampleContext {
+            added_at: HashMap::new(),
+            play_stats: HashMap::new(),
+            genres: HashMap::new(),
+        }
+    }
+
+    fn tracks(ids: &[&str]) -> Vec<FullTrack> {
+        ids.iter()
+            .map(|id| track(id, id, "Artist", "Album", 200000))
+            .collect_vec()
+    }
+
+    #[test]
+    fn weighted_sample_favours_heavy_tracks() {
+        for seed in 0..50 {
+            let chosen = weighted_sample(
+                tracks(&["a", "b", "heavy", "c", "d"]),
+                1,
+                |track| if track.name == "heavy" { 1e6 } else { 1.0 },
+                &mut seeded_rng(seed),
+            );
+            assert_eq!(sampled_ids(&chosen), ["heavy"]);
+        }
+    }
+
+    // Both weights would make u^(1/weight) zero
+    #[test]
+    fn weighted_sample_keeps_tiny_weights_apart() {
+        for seed in 0..50 {
+            let chosen = weighted_sample(
+                tracks(&["a", "heavy", "b"]),
+                1,
+                |track| {
+                    if track.name == "heavy" {
+                        1e-200
+                    } else {
+                        1e-250
+                    }
+                },
+                &mut seeded_rng(seed),
+            );
+            assert_eq!(sampled_ids(&chosen), ["heavy"]);
+        }
+    }
+
+    #[test]
+    fn weighted_sample_takes_each_track_once() {
+        let chosen = weighted_sample(
+            tracks(&["a", "b", "c", "d", "e"]),
+            3,
+            |_| 1.0,
+            &mut seeded_rng(7),
+        );
+        assert_eq!(chosen.len(), 3);
+        assert_eq!(sampled_ids(&chosen).iter().unique().count(), 3);
+    }
+
+    #[test]
+    fn stratified_sample_takes_from_every_group() {
+        let mut pool = (0..6)
+            .map(|index| track(&format!("big{}", index), "Song", "Artist", "Big", 200000))
+            .collect_vec();
+        pool.push(track("small", "Song", "Artist", "Small", 200000));
+
+        for seed in 0..20 {
+            let chosen = stratified_sample(
+                pool.clone(),
+                2,
+                |track| track.album.name.clone(),
+                &mut seeded_rng(seed),
+            );
+            assert_eq!(chosen.len(), 2);
+            assert!(sampled_ids(&chosen).contains(&String::from("small")));
+        }
+    }
+
+    #[test]
+    fn same_seed_gives_same_sample() {
+        let pool = (0..20).map(|index| format!("track{}", index)).collect_vec();
+        let pool = tracks(&pool.iter().map(String::as_str).collect_vec());
+        let sample = |strategy, seed| {
+            let options = SampleOptions {
+                strategy,
+                ..options()
+            };
+            sampled_ids(&sample_tracks(
+                pool.clone(),
+                &TrackLimit::Count(10),
+                &options,
+                &context(),
+                &mut seeded_rng(seed),
+            ))
+        };
+
+        for strategy in SamplingStrategy::value_variants() {
+            assert_eq!(sample(*strategy, 42), sample(*strategy, 42));
+        }
+        assert_ne!(
+            sample(SamplingStrategy::Uniform, 42),
+            sample(SamplingStrategy::Uniform, 43)
+        );
+    }
+
+    #[test]
+    fn cooldown_holds_back_recent_samples() {
+        let now = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let cooldown = Cooldown {
+            last_sampled: HashMap::from([
+                (String::from("old"), now - Duration::days(30)),
+                (String::from("edge"), now - Duration::days(7)),
+                (String::from("recent"), now - Duration::days(1)),
+            ]),
+            window: Duration::days(7),
+            now,
+        };
+        let sample = |num_songs| {
+            sampled_ids(&sample_without_repeats(
+                tracks(&["recent", "edge", "old", "new"]),
+                &cooldown,
+                &TrackLimit::Count(num_songs),
+                &options(),
+                &context(),
+                &mut seeded_rng(1),
+            ))
+        };
+
+        assert_eq!(sample(3), ["new", "old", "edge"]);
+        // Only once everything else is used
+        assert_eq!(sample(4), ["new", "old", "edge", "recent"]);
+    }
+}
@@ -0,0 +1,87 @@
+use rspotify::model::{Country, Market};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs};
+
+use super::dedupe::ReleasePreference;
+
+// Local rspot configuration, kept next to the library files in rspot_dir
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Settings {
+    #[serde(skip)]
+    path: String,
+
+    // Playlists created by rspot, keyed by the name they were requested under
+    #[serde(default)]
+    pub playlists: HashMap<String, String>,
+
+    // Spotify genres or top level genres mapped onto the top level genre they belong under,
+    // checked before the built in table
+    #[serde(default)]
+    pub genre_map: HashMap<String, String>,
+
+    // Which release of a song generated playlists keep when it's on several
+    #[serde(default)]
+    pub release_preference: ReleasePreference,
+
+    // Country code tracks are fetched and checked for, the account's country when not set
+    #[serde(default)]
+    pub market: Option<String>,
+}
+
+impl Settings {
+    pub fn load(path: String) -> Settings {
+        let mut settings = if std::path::Path::new(&path).exists() {
+            let contents = fs::read_to_string(&path).unwrap();
+            serde_json::from_str::<Settings>(&contents).unwrap()
+        } else {
+            Settings::default()
+        };
+        settings.path = path;
+        settings
+    }
+
+    pub fn store(&self) {
+        let serialized = serde_json::to_string_pretty(self).unwrap();
+        fs::write(&self.path, serialized).unwrap();
+    }
+
+    pub fn playlist_id(&self, key: &str) -> Option<&String> {
+        self.playlists.get(key)
+    }
+
+    pub fn record_playlist(&mut self, key: &str, playlist_id: &str) {
+        self.playlists
+            .insert(key.to_string(), playlist_id.to_string());
+        self.store();
+    }
+
+    pub fn market(&self) -> Market {
+        match &self.market {
+            Some(country) => parse_market(country).unwrap(),
+            None => Market::FromToken,
+        }
+    }
+
+    pub fn set_market(&mut self, country: Option<String>) {
+        self.market = country.map(|country| country.to_uppercase());
+        self.store();
+    }
+
+    pub fn set_release_preference(&mut self, preference: ReleasePreference) {
+        self.release_preference = preference;
+        self.store();
+    }
+
+    pub fn map_genre(&mut self, genre: &str, top_level: &str) {
+        self.genre_map
+            .insert(genre.to_lowercase(), top_level.to_string());
+        self.store();
+    }
+}
+
+// Takes an ISO 3166-1 alpha-2 country code like "AU" or "us"
+pub fn parse_market(country: &str) -> Result<Market, String> {
+    serde_json::from_value::<Country>(serde_json::Value::String(country.to_uppercase()))
+        .map(Market::Country)
+        .map_err(|_| format!("Unknown country code {}", country))
+}
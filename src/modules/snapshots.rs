@@ -0,0 +1,224 @@
+use chrono::{TimeZone, Utc};
+use futures::stream::TryStreamExt;
+use futures_util::pin_mut;
+use rspotify::{
+    model::{EpisodeId, PlayableId, PlayableItem, PlaylistId, TrackId},
+    prelude::*,
+    AuthCodeSpotify,
+};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::PathBuf};
+
+use super::conversion::unescape_html;
+
+// The state of a playlist before rspot modified it
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PlaylistSnapshot {
+    pub timestamp: i64,
+    pub playlist_id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub snapshot_id: String,
+    // Uris of the tracks and episodes in playlist order
+    pub items: Vec<String>,
+    // Local files and unavailable items, which can't be added back through the api
+    #[serde(default)]
+    pub skipped: Vec<String>,
+}
+
+// Every generator run snapshots its playlist, so only the newest of each playlist are kept
+const MAX_SNAPSHOTS: usize = 50;
+
+// Stores snapshots as rspot_dir/snapshots/<playlist id>/<timestamp>.json
+pub struct SnapshotStore {
+    snapshot_dir: PathBuf,
+}
+
+impl SnapshotStore {
+    pub fn new(snapshot_dir: PathBuf) -> SnapshotStore {
+        Self { snapshot_dir }
+    }
+
+    fn playlist_dir(&self, playlist_id: &str) -> PathBuf {
+        self.snapshot_dir.join(playlist_id)
+    }
+
+    pub async fn snapshot(&self, spotify: &AuthCodeSpotify, playlist_id: &str) -> PlaylistSnapshot {
+        let id = PlaylistId::from_id(playlist_id).unwrap();
+        let playlist = spotify.playlist(id.clone(), None, None).await.unwrap();
+
+        let stream = spotify.playlist_items(id, None, None);
+        pin_mut!(stream);
+
+        let mut items = Vec::new();
+        let mut skipped = Vec::new();
+        while let Some(item) = stream.try_next().await.unwrap() {
+            match item.track {
+                Some(PlayableItem::Track(track)) if track.id.is_some() => {
+                    items.push(track.id.unwrap().uri())
+                }
+                Some(PlayableItem::Episode(episode)) => items.push(episode.id.uri()),
+                Some(PlayableItem::Track(track)) => skipped.push(match track.artists.get(0) {
+                    Some(artist) => format!("{} - {}", artist.name, track.name),
+                    None => track.name,
+                }),
+                None => skipped.push(String::from("Unknown item")),
+            }
+        }
+        if !skipped.is_empty() {
+            println!(
+                "{} local or unavailable items in {} can't be restored",
+                skipped.len(),
+                playlist.name
+            );
+        }
+
+        // Milliseconds so steps of the same run don't overwrite each other, bumped in the unlikely
+        // case a snapshot with the time is already there
+        let dir = self.playlist_dir(playlist_id);
+        fs::create_dir_all(&dir).unwrap();
+        let mut timestamp = Utc::now().timestamp_millis();
+        while dir.join(format!("{}.json", timestamp)).exists() {
+            timestamp += 1;
+        }
+
+        let snapshot = PlaylistSnapshot {
+            timestamp,
+            playlist_id: playlist_id.to_string(),
+            name: playlist.name,
+            description: playlist.description,
+            snapshot_id: playlist.snapshot_id,
+            items,
+            skipped,
+        };
+
+        let serialized = serde_json::to_string(&snapshot).unwrap();
+        fs::write(dir.join(format!("{}.json", snapshot.timestamp)), serialized).unwrap();
+        self.prune(playlist_id);
+        snapshot
+    }
+
+    // Deletes all but the newest MAX_SNAPSHOTS snapshots of the playlist
+    fn prune(&self, playlist_id: &str) {
+        let timestamps = self.history(playlist_id);
+        let num_old = timestamps.len().saturating_sub(MAX_SNAPSHOTS);
+        for timestamp in &timestamps[..num_old] {
+            fs::remove_file(self.snapshot_path(playlist_id, *timestamp)).unwrap();
+        }
+    }
+
+    fn snapshot_path(&self, playlist_id: &str, timestamp: i64) -> PathBuf {
+        self.playlist_dir(playlist_id)
+            .join(format!("{}.json", timestamp))
+    }
+
+    pub fn history(&self, playlist_id: &str) -> Vec<i64> {
+        let dir = self.playlist_dir(playlist_id);
+        if !dir.exists() {
+            return Vec::new();
+        }
+
+        let mut timestamps = fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|entry| {
+                let path = entry.unwrap().path();
+                path.file_stem()?.to_str()?.parse::<i64>().ok()
+            })
+            .collect::<Vec<_>>();
+        timestamps.sort();
+        timestamps
+    }
+
+    pub fn load(&self, playlist_id: &str, timestamp: i64) -> Result<PlaylistSnapshot, String> {
+        let contents = fs::read_to_string(self.snapshot_path(playlist_id, timestamp))
+            .map_err(|_| format!("No snapshot {} for {}", timestamp, playlist_id))?;
+        serde_json::from_str::<PlaylistSnapshot>(&contents)
+            .map_err(|err| format!("Snapshot {} is unreadable: {}", timestamp, err))
+    }
+
+    pub fn print_history(&self, playlist_id: &str) {
+        for timestamp in self.history(playlist_id) {
+            match self.load(playlist_id, timestamp) {
+                Ok(snapshot) => println!(
+                    "{}  {}  {} - {} items",
+                    timestamp,
+                    Utc.timestamp_millis_opt(timestamp).unwrap().to_rfc3339(),
+                    snapshot.name,
+                    snapshot.items.len()
+                ),
+                Err(err) => println!("{}", err),
+            }
+        }
+    }
+
+    // Replaces the playlist with the snapshot, snapshotting the current state first so a restore
+    // can itself be rolled back
+    pub async fn restore(
+        &self,
+        spotify: &AuthCodeSpotify,
+        playlist_id: &str,
+        timestamp: i64,
+    ) -> Result<(), String> {
+        let snapshot = self.load(playlist_id, timestamp)?;
+        self.snapshot(spotify, playlist_id).await;
+
+        let id = PlaylistId::from_id(playlist_id).unwrap();
+        let items = snapshot
+            .items
+            .iter()
+            .map(|uri| uri_to_playable_id(uri))
+            .collect::<Vec<_>>();
+
+        let mut chunks = items.chunks(100);
+        spotify
+            .playlist_replace_items(id.clone(), chunks.next().unwrap_or(&[]).iter().cloned())
+            .await
+            .unwrap();
+        for chunk in chunks {
+            spotify
+                .playlist_add_items(id.clone(), chunk.iter().cloned(), None)
+                .await
+                .unwrap();
+        }
+
+        spotify
+            .playlist_change_detail(
+                id,
+                Some(&snapshot.name),
+                None,
+                snapshot
+                    .description
+                    .as_deref()
+                    .map(unescape_html)
+                    .as_deref(),
+                None,
+            )
+            .await
+            .unwrap();
+
+        println!(
+            "Restored {} to {} items from {}",
+            snapshot.name,
+            snapshot.items.len(),
+            timestamp
+        );
+        if !snapshot.skipped.is_empty() {
+            println!(
+                "{} local or unavailable items weren't restored:",
+                snapshot.skipped.len()
+            );
+            for name in &snapshot.skipped {
+                println!("  {}", name);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn uri_to_playable_id(uri: &str) -> PlayableId<'_> {
+    if uri.starts_with("spotify:episode:") {
+        PlayableId::Episode(EpisodeId::from_uri(uri).unwrap())
+    } else {
+        PlayableId::Track(TrackId::from_uri(uri).unwrap())
+    }
+}
@@ -1,15 +1,19 @@
-use chrono::{TimeZone, Utc};
+use chrono::{DateTime, TimeZone, Utc};
 use itertools::Itertools;
 use rspotify::{
-    model::{FullAlbum, FullTrack, TrackId},
+    model::{ArtistId, FullAlbum, FullArtist, FullEpisode, FullTrack, Market, SavedTrack, Show},
     prelude::*,
     AuthCodeSpotify,
 };
 use serde::Deserialize;
-use std::{collections::HashMap, fs};
+use std::{collections::HashMap, fs, path::PathBuf};
 
 use super::{
     conversion::{saved_albums_to_albums, saved_tracks_to_tracks},
+    features::TrackFeatures,
+    history::{compile_stats, Play, PlayStats},
+    local::{local_key, track_key},
+    playlists::playlist_track_id,
     retrieve,
 };
 
@@ -17,14 +21,35 @@ pub struct LibraryDatabase {
     album_path: String,
     track_path: String,
     liked_path: String,
+    history_path: String,
+    cursor_path: String,
+    sampled_path: String,
+    added_path: String,
+    features_path: String,
+    artist_path: String,
+    show_path: String,
+    episode_path: String,
+    local_path: String,
+    market: Market,
 }
 
 impl LibraryDatabase {
-    pub fn new(album_path: String, track_path: String, liked_path: String) -> LibraryDatabase {
+    pub fn new(rspot_dir: &PathBuf, market: Market) -> LibraryDatabase {
+        let path = |filename: &str| rspot_dir.join(filename).to_str().unwrap().to_string();
         Self {
-            album_path,
-            track_path,
-            liked_path,
+            album_path: path("albums.json"),
+            track_path: path("tracks.json"),
+            liked_path: path("liked.json"),
+            history_path: path("history.json"),
+            cursor_path: path("cursors.json"),
+            sampled_path: path("sampled.json"),
+            added_path: path("added.json"),
+            features_path: path("features.json"),
+            artist_path: path("artists.json"),
+            show_path: path("shows.json"),
+            episode_path: path("episodes.json"),
+            local_path: path("local.json"),
+            market,
         }
     }
 
@@ -50,16 +75,36 @@ impl LibraryDatabase {
         deserialized
     }
 
-    fn update_tracks(&self, tracks: Vec<FullTrack>) {
+    // The market tracks are fetched for, which decides their is_playable and relinking
+    pub fn market(&self) -> Market {
+        self.market
+    }
+
+    // Local files are kept apart so everything reading the tracks can count on them having an id.
+    // Tracks are stored under the id they were saved with, a relinked track has another id
+    pub fn update_tracks(&self, tracks: Vec<FullTrack>) {
         // These should probably be env variables
         let mut current_tracks = self.retrieve_tracks();
+        let (local, tracks): (Vec<_>, Vec<_>) =
+            tracks.into_iter().partition(|track| track.is_local);
         for track in tracks {
-            if !track.is_local {
-                current_tracks.insert(track.id.clone().unwrap().id().to_string(), track);
-            }
+            current_tracks.insert(track_key(&track), track);
+        }
+
+        Self::store_hashmap(&current_tracks, &self.track_path);
+        self.update_local(local);
+    }
+
+    fn update_local(&self, tracks: Vec<FullTrack>) {
+        if tracks.is_empty() {
+            return;
+        }
+        let mut current_tracks = self.retrieve_local();
+        for track in tracks {
+            current_tracks.insert(local_key(&track), track);
         }
 
-        Self::store_hashmap(&current_tracks, &self.track_path)
+        Self::store_hashmap(&current_tracks, &self.local_path);
     }
 
     fn update_albums(&self, albums: Vec<FullAlbum>) {
@@ -73,17 +118,32 @@ impl LibraryDatabase {
 
     fn update_liked(&self, tracks: Vec<FullTrack>) {
         let mut current_tracks = self.retrieve_liked();
+        let (local, tracks): (Vec<_>, Vec<_>) =
+            tracks.into_iter().partition(|track| track.is_local);
         for track in tracks {
-            current_tracks.insert(track.id.clone().unwrap().id().to_string(), track);
+            current_tracks.insert(track_key(&track), track);
         }
 
         Self::store_hashmap(&current_tracks, &self.liked_path);
+        self.update_local(local);
+    }
+
+    // Only the saved entries know when they were added, so the dates are stored alongside
+    fn update_added(&self, saved: &[SavedTrack]) {
+        let mut added = self.retrieve_added();
+        for saved_track in saved {
+            if let Some(id) = playlist_track_id(&saved_track.track) {
+                added.insert(id.id().to_string(), saved_track.added_at);
+            }
+        }
+
+        Self::store_hashmap(&added, &self.added_path);
     }
 
     pub async fn update_all(&self, spotify: &AuthCodeSpotify) {
-        self.update_tracks(saved_tracks_to_tracks(
-            retrieve::recently_added_tracks(spotify, self, None).await,
-        ));
+        let recent_tracks = retrieve::recently_added_tracks(spotify, self, None).await;
+        self.update_added(&recent_tracks);
+        self.update_tracks(saved_tracks_to_tracks(recent_tracks));
         self.update_albums(saved_albums_to_albums(
             retrieve::recently_added_albums(spotify, self, None).await,
         ));
@@ -92,6 +152,93 @@ impl LibraryDatabase {
             retrieve::recently_liked_tracks(spotify, self, Some(&Utc.timestamp_opt(0, 0).unwrap()))
                 .await,
         ));
+        self.update_features(spotify).await;
+        self.update_artists(spotify).await;
+        self.update_shows(spotify).await;
+    }
+
+    // Saved shows are stored as they are now, and any episodes of theirs that aren't stored yet
+    // are fetched
+    async fn update_shows(&self, spotify: &AuthCodeSpotify) {
+        let shows = retrieve::saved_shows(spotify).await;
+        let mut episodes = self.retrieve_episodes();
+        let mut missing = Vec::new();
+        for show in &shows {
+            let mut show_episodes =
+                retrieve::new_show_episodes(spotify, self, show.show.id.clone(), &episodes).await;
+            missing.append(&mut show_episodes);
+        }
+
+        if !missing.is_empty() {
+            let fetched = retrieve::episodes(spotify, &missing, Some(self.market)).await;
+            println!("Fetched {} new episodes", fetched.len());
+            for episode in fetched {
+                episodes.insert(episode.id.id().to_string(), episode);
+            }
+            Self::store_hashmap(&episodes, &self.episode_path);
+        }
+
+        let shows = shows
+            .into_iter()
+            .map(|show| (show.show.id.id().to_string(), show))
+            .collect::<HashMap<_, _>>();
+        Self::store_hashmap(&shows, &self.show_path);
+    }
+
+    // Fetches audio features for the stored tracks that don't have them yet
+    async fn update_features(&self, spotify: &AuthCodeSpotify) {
+        let mut features = self.retrieve_features();
+        let missing = self
+            .retrieve_tracks()
+            .into_values()
+            .filter_map(|track| track.id)
+            .filter(|id| !features.contains_key(id.id()))
+            .collect_vec();
+        if missing.is_empty() {
+            return;
+        }
+
+        let fetched = retrieve::audio_features(spotify, missing).await;
+        println!("Fetched audio features for {} tracks", fetched.len());
+        for track_features in fetched {
+            features.insert(
+                track_features.id.id().to_string(),
+                TrackFeatures::from_audio_features(&track_features),
+            );
+        }
+
+        Self::store_hashmap(&features, &self.features_path);
+    }
+
+    // Caches the artists of the stored tracks and albums, their genres are what tracks get
+    // tagged with
+    async fn update_artists(&self, spotify: &AuthCodeSpotify) {
+        let mut artists = self.retrieve_artists();
+        let track_artists = self
+            .retrieve_tracks()
+            .into_values()
+            .flat_map(|track| track.artists);
+        let album_artists = self
+            .retrieve_albums()
+            .into_values()
+            .flat_map(|album| album.artists);
+        let missing: Vec<ArtistId<'static>> = track_artists
+            .chain(album_artists)
+            .filter_map(|artist| artist.id)
+            .filter(|id| !artists.contains_key(id.id()))
+            .unique()
+            .collect_vec();
+        if missing.is_empty() {
+            return;
+        }
+
+        let fetched = retrieve::artists(spotify, missing).await;
+        println!("Fetched {} artists", fetched.len());
+        for artist in fetched {
+            artists.insert(artist.id.id().to_string(), artist);
+        }
+
+        Self::store_hashmap(&artists, &self.artist_path);
     }
 
     pub fn retrieve_albums(&self) -> HashMap<String, FullAlbum> {
@@ -106,22 +253,112 @@ impl LibraryDatabase {
         Self::load_hashmap::<FullTrack>(&self.liked_path)
     }
 
-    pub fn compile_genres(albums: Vec<FullAlbum>) -> HashMap<String, Vec<TrackId<'static>>> {
+    // Saved local files keyed by their tags
+    pub fn retrieve_local(&self) -> HashMap<String, FullTrack> {
+        Self::load_hashmap::<FullTrack>(&self.local_path)
+    }
+
+    pub fn retrieve_added(&self) -> HashMap<String, DateTime<Utc>> {
+        Self::load_hashmap::<DateTime<Utc>>(&self.added_path)
+    }
+
+    pub fn retrieve_features(&self) -> HashMap<String, TrackFeatures> {
+        Self::load_hashmap::<TrackFeatures>(&self.features_path)
+    }
+
+    pub fn retrieve_artists(&self) -> HashMap<String, FullArtist> {
+        Self::load_hashmap::<FullArtist>(&self.artist_path)
+    }
+
+    pub fn retrieve_shows(&self) -> HashMap<String, Show> {
+        Self::load_hashmap::<Show>(&self.show_path)
+    }
+
+    pub fn retrieve_episodes(&self) -> HashMap<String, FullEpisode> {
+        Self::load_hashmap::<FullEpisode>(&self.episode_path)
+    }
+
+    // Genres of each track, taken from its artists and the album it's on. Albums hardly ever
+    // have genres so the artists are where most of them come from
+    pub fn track_genres(&self) -> HashMap<String, Vec<String>> {
+        let artists = self.retrieve_artists();
+        let albums = self.retrieve_albums();
         let mut genres = HashMap::new();
-        for album in albums {
-            println!("{:?}", album.genres);
-            let track_ids = album
-                .tracks
-                .items
+        for (id, track) in self.retrieve_tracks() {
+            let artist_genres = track
+                .artists
                 .iter()
-                .map(|track| track.id.as_ref().unwrap().to_owned())
-                .collect_vec();
-            for genre in album.genres {
-                println!("{}: {:?}", genre, track_ids);
-                genres
-                    .entry(genre)
-                    .or_insert(Vec::new())
-                    .append(track_ids.clone().as_mut());
+                .filter_map(|artist| artist.id.as_ref())
+                .filter_map(|artist_id| artists.get(artist_id.id()))
+                .flat_map(|artist| artist.genres.iter().cloned());
+            let album_genres = track
+                .album
+                .id
+                .as_ref()
+                .and_then(|album_id| albums.get(album_id.id()))
+                .map(|album| album.genres.clone())
+                .unwrap_or_default();
+            let track_genres = artist_genres.chain(album_genres).unique().collect_vec();
+            if !track_genres.is_empty() {
+                genres.insert(id, track_genres);
+            }
+        }
+        genres
+    }
+
+    pub fn retrieve_history(&self) -> HashMap<String, Play> {
+        Self::load_hashmap::<Play>(&self.history_path)
+    }
+
+    // Returns how many of the plays weren't already stored
+    pub fn update_history(&self, plays: Vec<Play>) -> usize {
+        let mut current_plays = self.retrieve_history();
+        let num_plays = current_plays.len();
+        for play in plays {
+            current_plays.insert(play.key(), play);
+        }
+
+        let added = current_plays.len() - num_plays;
+        Self::store_hashmap(&current_plays, &self.history_path);
+        added
+    }
+
+    // When each track was last put in a weekly sample
+    pub fn retrieve_sampled(&self) -> HashMap<String, DateTime<Utc>> {
+        Self::load_hashmap::<DateTime<Utc>>(&self.sampled_path)
+    }
+
+    pub fn update_sampled(&self, track_ids: Vec<String>, sampled_at: DateTime<Utc>) {
+        let mut sampled = self.retrieve_sampled();
+        for track_id in track_ids {
+            sampled.insert(track_id, sampled_at);
+        }
+
+        Self::store_hashmap(&sampled, &self.sampled_path);
+    }
+
+    // Cursors let incremental fetches carry on from where the last run stopped
+    pub fn retrieve_cursor(&self, name: &str) -> Option<String> {
+        Self::load_hashmap::<String>(&self.cursor_path).remove(name)
+    }
+
+    pub fn update_cursor(&self, name: &str, cursor: String) {
+        let mut cursors = Self::load_hashmap::<String>(&self.cursor_path);
+        cursors.insert(name.to_string(), cursor);
+        Self::store_hashmap(&cursors, &self.cursor_path);
+    }
+
+    // Play stats keyed by track id
+    pub fn play_stats(&self) -> HashMap<String, PlayStats> {
+        compile_stats(self.retrieve_history().values())
+    }
+
+    // Track ids under each genre
+    pub fn compile_genres(&self) -> HashMap<String, Vec<String>> {
+        let mut genres: HashMap<String, Vec<String>> = HashMap::new();
+        for (track_id, track_genres) in self.track_genres() {
+            for genre in track_genres {
+                genres.entry(genre).or_default().push(track_id.clone());
             }
         }
         genres
@@ -1,3 +1,4 @@
+use itertools::Itertools;
 use rspotify::{prelude::*, scopes, AuthCodeSpotify, Config, Credentials, OAuth, Token};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -28,10 +29,15 @@ pub async fn authcode(
 }
 
 async fn generate_token(creds: Credentials, oauth: OAuth, path: PathBuf) -> Result<Token, ()> {
-    // Doesn't check if valid creds, just if the token exists.
+    // Doesn't check if valid creds, just if the token exists. A token from before a scope was
+    // added can't be refreshed into one with it, so that takes logging in again
     match Token::from_cache(path.clone()) {
-        Ok(token) => Ok(token),
-        Err(_) => {
+        Ok(token) if oauth.scopes.is_subset(&token.scopes) => Ok(token),
+        cached => {
+            if let Ok(token) = cached {
+                let missing = oauth.scopes.difference(&token.scopes).join(", ");
+                println!("The saved login is missing {}, log in again", missing);
+            }
             let spotify = AuthCodeSpotify::new(creds.clone(), oauth.clone());
             let url = spotify.get_authorize_url(false).unwrap();
             // This function requires the `cli` feature enabled.
@@ -102,7 +108,15 @@ pub async fn default_authcode(rspot_dir: &PathBuf) -> Result<AuthCodeSpotify, ()
     let creds = Credentials::new(&auth_details.client_id, &auth_details.client_secret);
     let oauth = OAuth {
         redirect_uri: auth_details.redirect_uri,
-        scopes: scopes!("user-follow-read user-follow-modify"),
+        scopes: scopes!(
+            "user-follow-read",
+            "user-follow-modify",
+            "playlist-modify-private",
+            "playlist-modify-public",
+            "user-read-recently-played",
+            "user-read-playback-position",
+            "user-library-read"
+        ),
         ..Default::default()
     };
 
@@ -120,7 +134,16 @@ pub async fn default_authcode(rspot_dir: &PathBuf) -> Result<AuthCodeSpotify, ()
 
 pub fn obtain_env_details() -> (Credentials, OAuth, Config) {
     let creds = Credentials::from_env().unwrap();
-    let oauth = OAuth::from_env(scopes!("user-follow-read user-follow-modify")).unwrap();
+    let oauth = OAuth::from_env(scopes!(
+        "user-follow-read",
+        "user-follow-modify",
+        "playlist-modify-private",
+        "playlist-modify-public",
+        "user-read-recently-played",
+        "user-read-playback-position",
+        "user-library-read"
+    ))
+    .unwrap();
     print!("oauth: {:?}", oauth);
     let path = PathBuf::from("token_cache.json");
     let config = Config {